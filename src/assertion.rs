@@ -0,0 +1,332 @@
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+use crate::resolve::plain_string;
+use crate::types::{Assertion, JsonType};
+
+/// An operator that does not hold for a value.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Mismatch {
+    /// Operator, e.g. `gt`
+    pub operator: &'static str,
+    /// Operand of the operator
+    pub expected: Value,
+    /// Value found, null when there is none
+    pub actual: Value,
+    pub message: String,
+}
+
+/// Checks every operator of an assertion against `actual`, `None` meaning nothing was
+/// found, and returns those that do not hold.
+pub fn check(assertion: &Assertion, subject: &str, actual: Option<&Value>) -> Vec<Mismatch> {
+    let mismatch = |operator: &'static str, expected: Value, message: String| Mismatch {
+        operator,
+        expected,
+        actual: actual.cloned().unwrap_or(Value::Null),
+        message,
+    };
+    // The operator deciding whether a value must be found, and whether it must
+    let existence = match (assertion.exists, assertion.not_exists) {
+        (Some(exists), _) => Some(("exists", exists, exists)),
+        (None, Some(not_exists)) => Some(("not_exists", not_exists, !not_exists)),
+        (None, None) => None,
+    };
+    let actual = match (actual, existence) {
+        (Some(actual), None | Some((_, _, true))) => actual,
+        (None, Some((_, _, false))) => return Vec::new(),
+        (Some(_), Some((operator, operand, false))) => {
+            let message = format!("expected {} not to exist", subject);
+            return vec![mismatch(operator, Value::Bool(operand), message)];
+        }
+        (None, Some((operator, operand, true))) => {
+            let message = format!("expected {} to exist", subject);
+            return vec![mismatch(operator, Value::Bool(operand), message)];
+        }
+        (None, None) => {
+            return vec![mismatch("exists", Value::Bool(true), format!("{} not found", subject))];
+        }
+    };
+
+    let mut mismatches = Vec::new();
+    let mut fail = |operator: &'static str, expected: &Value, expectation: String| {
+        let message = format!("expected {} {} but got {}", subject, expectation, preview(actual));
+        mismatches.push(mismatch(operator, expected.clone(), message));
+    };
+
+    if let Some(expected) = operand(&assertion.value) {
+        if plain_string(actual) != plain_string(&expected) {
+            fail("value", &expected, format!("to be {}", plain_string(&expected)));
+        }
+    }
+    if let Some(expected) = operand(&assertion.equals) {
+        if !same(actual, &expected) {
+            fail("equals", &expected, format!("to equal {}", expected));
+        }
+    }
+    if let Some(expected) = operand(&assertion.not_equals) {
+        if same(actual, &expected) {
+            fail("not_equals", &expected, format!("not to equal {}", expected));
+        }
+    }
+    let comparisons = [
+        ("gt", &assertion.gt, "greater than", &[Ordering::Greater][..]),
+        ("gte", &assertion.gte, "at least", &[Ordering::Greater, Ordering::Equal][..]),
+        ("lt", &assertion.lt, "less than", &[Ordering::Less][..]),
+        ("lte", &assertion.lte, "at most", &[Ordering::Less, Ordering::Equal][..]),
+    ];
+    for (operator, operand_value, description, accepted) in comparisons {
+        let Some(expected) = operand(operand_value) else {
+            continue;
+        };
+        match compare(actual, &expected) {
+            Some(ordering) if accepted.contains(&ordering) => {}
+            Some(_) => fail(operator, &expected, format!("to be {} {}", description, expected)),
+            None => fail(operator, &expected, format!("to be comparable with {}", expected)),
+        }
+    }
+    if let Some(pattern) = &assertion.matches {
+        let expected = Value::String(pattern.clone());
+        match Regex::new(pattern) {
+            Ok(regex) if regex.is_match(&plain_string(actual)) => {}
+            Ok(_) => fail("matches", &expected, format!("to match `{}`", pattern)),
+            Err(e) => {
+                let expectation = format!("to match `{}` (invalid regex: {})", pattern, e);
+                fail("matches", &expected, expectation)
+            }
+        }
+    }
+    if let Some(expected) = assertion.is {
+        if !is_type(actual, expected) {
+            let name = serde_json::to_value(expected).unwrap_or_default();
+            fail("is", &name, format!("to be {}", type_name(expected)));
+        }
+    }
+    if let Some(expected) = operand(&assertion.length) {
+        let length = match actual {
+            Value::String(s) => Some(s.chars().count()),
+            Value::Array(items) => Some(items.len()),
+            Value::Object(map) => Some(map.len()),
+            _ => None,
+        };
+        match length {
+            Some(length) if expected.as_u64() == Some(length as u64) => {}
+            Some(length) => {
+                let expectation = format!("to have length {} (length {})", expected, length);
+                fail("length", &expected, expectation)
+            }
+            None => fail("length", &expected, format!("to have length {}", expected)),
+        }
+    }
+    if let Some(expected) = operand(&assertion.contains) {
+        let contained = match (actual, &expected) {
+            (Value::String(s), Value::String(part)) => s.contains(part.as_str()),
+            (Value::String(s), part) => s.contains(&part.to_string()),
+            (Value::Array(items), _) => items.iter().any(|item| same(item, &expected)),
+            (Value::Object(map), Value::String(key)) => map.contains_key(key),
+            _ => false,
+        };
+        if !contained {
+            fail("contains", &expected, format!("to contain {}", expected));
+        }
+    }
+    if let Some(expected) = operand(&assertion.one_of) {
+        let found = match &expected {
+            Value::Array(options) => options.iter().any(|option| same(actual, option)),
+            _ => false,
+        };
+        if !found {
+            fail("in", &expected, format!("to be one of {}", expected));
+        }
+    }
+    mismatches
+}
+
+/// Validates a value against a JSON Schema. Returns every violation with the JSON
+/// pointer of the offending value; `expected` is the location in the schema of the
+/// keyword that failed.
+pub fn check_schema(schema: &Value, instance: &Value) -> Vec<(String, Mismatch)> {
+    let validator = match jsonschema::validator_for(schema) {
+        Ok(validator) => validator,
+        Err(e) => {
+            let mismatch = Mismatch {
+                operator: "schema",
+                expected: Value::Null,
+                actual: Value::Null,
+                message: format!("invalid JSON Schema: {}", e),
+            };
+            return vec![("/".to_string(), mismatch)];
+        }
+    };
+    validator
+        .iter_errors(instance)
+        .map(|e| {
+            let pointer = e.instance_path().to_string();
+            let pointer = if pointer.is_empty() { "/".to_string() } else { pointer };
+            let mismatch = Mismatch {
+                operator: "schema",
+                expected: Value::String(e.schema_path().to_string()),
+                actual: e.instance().clone().into_owned(),
+                message: format!("{}: {}", pointer, e),
+            };
+            (pointer, mismatch)
+        })
+        .collect()
+}
+
+fn operand(value: &Option<serde_yaml::Value>) -> Option<Value> {
+    // Mappings with keys that are not strings have no JSON form and become null
+    value.as_ref().map(|value| serde_json::to_value(value).unwrap_or_default())
+}
+
+/// Equality of JSON values, where numbers compare by value (`1` equals `1.0`).
+fn same(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+fn is_type(value: &Value, expected: JsonType) -> bool {
+    match expected {
+        JsonType::String => value.is_string(),
+        JsonType::Number => value.is_number(),
+        JsonType::Integer => value.is_i64() || value.is_u64(),
+        JsonType::Boolean => value.is_boolean(),
+        JsonType::Array => value.is_array(),
+        JsonType::Object => value.is_object(),
+        JsonType::Null => value.is_null(),
+    }
+}
+
+fn type_name(expected: JsonType) -> &'static str {
+    match expected {
+        JsonType::String => "a string",
+        JsonType::Number => "a number",
+        JsonType::Integer => "an integer",
+        JsonType::Boolean => "a boolean",
+        JsonType::Array => "an array",
+        JsonType::Object => "an object",
+        JsonType::Null => "null",
+    }
+}
+
+/// JSON text of a value, shortened for error messages.
+fn preview(value: &Value) -> String {
+    let text = value.to_string();
+    match text.char_indices().nth(100) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn messages(yaml: &str, actual: Option<Value>) -> Vec<String> {
+        let assertion: Assertion = serde_yaml::from_str(yaml).unwrap();
+        check(&assertion, "x", actual.as_ref())
+            .into_iter()
+            .map(|mismatch| mismatch.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_operators() {
+        let passing = [
+            ("equals: 3", json!(3.0)),
+            ("value: '3'", json!(3)),
+            ("not_equals: '3'", json!(3)),
+            ("{ gt: 1, lte: 3 }", json!(3)),
+            ("lt: b", json!("a")),
+            ("matches: '^\\d{3}-\\w+$'", json!("123-abc")),
+            ("is: integer", json!(2)),
+            ("length: 2", json!(["a", "b"])),
+            ("contains: ell", json!("hello")),
+            ("contains: { id: 1 }", json!([{"id": 1}])),
+            ("contains: id", json!({"id": 1})),
+            ("in: [active, pending]", json!("pending")),
+            ("one_of: [1, 2]", json!(2)),
+        ];
+        for (yaml, actual) in passing {
+            assert_eq!(messages(yaml, Some(actual)), Vec::<String>::new(), "{}", yaml);
+        }
+
+        let failing = [
+            ("equals: '3'", json!(3), "expected x to equal \"3\" but got 3"),
+            ("gt: 5", json!(3), "expected x to be greater than 5 but got 3"),
+            ("gt: 5", json!("7"), "expected x to be comparable with 5 but got \"7\""),
+            ("is: string", json!(null), "expected x to be a string but got null"),
+            ("length: 1", json!("ab"), "expected x to have length 1 (length 2) but got \"ab\""),
+            ("in: [1, 2]", json!(3), "expected x to be one of [1,2] but got 3"),
+            ("exists: false", json!(1), "expected x not to exist"),
+        ];
+        for (yaml, actual, reason) in failing {
+            assert_eq!(messages(yaml, Some(actual)), vec![reason]);
+        }
+    }
+
+    #[test]
+    fn test_every_failing_operator_is_reported() {
+        let assertion: Assertion = serde_yaml::from_str("{ gt: 5, is: string, lt: 10 }").unwrap();
+        let mismatches = check(&assertion, "x", Some(&json!(3)));
+
+        assert_eq!(
+            mismatches,
+            vec![
+                Mismatch {
+                    operator: "gt",
+                    expected: json!(5),
+                    actual: json!(3),
+                    message: "expected x to be greater than 5 but got 3".to_string(),
+                },
+                Mismatch {
+                    operator: "is",
+                    expected: json!("string"),
+                    actual: json!(3),
+                    message: "expected x to be a string but got 3".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_schema() {
+        let schema = json!({
+            "type": "object",
+            "required": ["id", "name"],
+            "properties": {
+                "id": {"type": "integer"},
+                "tags": {"type": "array", "items": {"type": "string"}}
+            }
+        });
+
+        assert!(check_schema(&schema, &json!({"id": 1, "name": "a", "tags": []})).is_empty());
+        let violations = check_schema(&schema, &json!({"id": "1", "tags": ["a", 2]}));
+        let pointers: Vec<&str> = violations.iter().map(|(pointer, _)| pointer.as_str()).collect();
+        assert_eq!(pointers, vec!["/", "/id", "/tags/1"]);
+        assert_eq!(violations[1].1.message, "/id: \"1\" is not of type \"integer\"");
+        assert_eq!(violations[1].1.expected, json!("/properties/id/type"));
+        assert_eq!(violations[2].1.actual, json!(2));
+        assert_eq!(check_schema(&json!({"type": "nope"}), &json!(1)).len(), 1);
+    }
+
+    #[test]
+    fn test_missing_values() {
+        assert!(messages("not_exists: true", None).is_empty());
+        assert!(messages("exists: false", None).is_empty());
+        assert_eq!(messages("exists: true", None), vec!["expected x to exist"]);
+        assert_eq!(messages("equals: 1", None), vec!["x not found"]);
+    }
+}
@@ -0,0 +1,862 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{debug, info};
+use md5::Md5;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, WWW_AUTHENTICATE};
+use serde::Deserialize;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::types::{
+    Auth, AwsSigV4, BasicAuth, HmacAlgorithm, HmacAuth, OAuth2Client, SignatureEncoding,
+};
+
+/// Tokens are fetched again this long before they expire, so they do not expire
+/// while a request is on its way.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    access_token: String,
+    expires_at: Option<Instant>,
+    refresh_token: Option<String>,
+}
+
+impl Token {
+    fn is_fresh(&self) -> bool {
+        self.expires_at
+            .is_none_or(|expires_at| Instant::now() + EXPIRY_MARGIN < expires_at)
+    }
+}
+
+/// A digest challenge of a server, answered by every following request to it with
+/// an increasing nonce count.
+#[derive(Debug, Clone, PartialEq)]
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    /// Algorithm as sent by the server, e.g. `SHA-256-sess`
+    algorithm: String,
+    qop: Option<&'static str>,
+    count: u32,
+}
+
+impl DigestChallenge {
+    fn from_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let algorithm = params.get("algorithm").cloned().unwrap_or_else(|| "MD5".to_string());
+        digest_hash(&algorithm)?;
+        // `auth-int` would need the body, and is hardly ever the only choice
+        let qop = match params.get("qop") {
+            None => None,
+            Some(qop) if qop.split(',').any(|qop| qop.trim() == "auth") => Some("auth"),
+            Some(qop) => return Err(format!("unsupported digest qop `{}`", qop)),
+        };
+        Ok(DigestChallenge {
+            realm: params.get("realm").cloned().unwrap_or_default(),
+            nonce: params.get("nonce").cloned().ok_or("digest challenge without a nonce")?,
+            opaque: params.get("opaque").cloned(),
+            algorithm,
+            qop,
+            count: 0,
+        })
+    }
+}
+
+/// OAuth2 tokens fetched and digest challenges received during a run, shared by
+/// every task using the same client and credentials.
+#[derive(Clone, Default)]
+pub struct Tokens {
+    tokens: Arc<Mutex<HashMap<String, Token>>>,
+    challenges: Arc<Mutex<HashMap<String, DigestChallenge>>>,
+}
+
+impl Tokens {
+    /// Returns the `Authorization` header value for a task's credentials, fetching an
+    /// OAuth2 token if there is no fresh one. Signatures are not known until the
+    /// request is built, and are added by `sign`.
+    pub async fn authorization(
+        &self,
+        auth: &Auth,
+        client: &reqwest::Client,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let (oauth2, grant) = match auth {
+            Auth::Basic(basic) => {
+                let credentials = format!("{}:{}", basic.username, basic.password);
+                return Ok(Some(format!("Basic {}", BASE64.encode(credentials))));
+            }
+            Auth::Bearer { token } => return Ok(Some(format!("Bearer {}", token))),
+            Auth::Digest(_) | Auth::AwsSigV4(_) | Auth::Hmac(_) => return Ok(None),
+            Auth::OAuth2ClientCredentials(oauth2) => {
+                (oauth2, vec![("grant_type", "client_credentials")])
+            }
+            Auth::OAuth2Password { client, username, password } => (
+                client,
+                vec![
+                    ("grant_type", "password"),
+                    ("username", username.as_str()),
+                    ("password", password.as_str()),
+                ],
+            ),
+        };
+
+        // Holding the lock while fetching makes concurrent tasks wait for one token
+        // instead of each requesting their own
+        let key = cache_key(auth);
+        let mut tokens = self.tokens.lock().await;
+        let cached = tokens.get(&key).cloned();
+        if let Some(token) = cached.as_ref().filter(|token| token.is_fresh()) {
+            return Ok(Some(format!("Bearer {}", token.access_token)));
+        }
+
+        let refreshed = match cached.and_then(|token| token.refresh_token) {
+            Some(refresh_token) => {
+                let grant = [("grant_type", "refresh_token"), ("refresh_token", &refresh_token)];
+                match request_token(client, oauth2, &grant).await {
+                    Ok(token) => Some(token),
+                    Err(e) => {
+                        debug!("Refreshing the token failed, requesting a new one: {}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        let token = match refreshed {
+            Some(token) => token,
+            None => request_token(client, oauth2, &grant).await?,
+        };
+        let authorization = format!("Bearer {}", token.access_token);
+        tokens.insert(key, token);
+        Ok(Some(authorization))
+    }
+
+    /// Drops the cached token for these credentials, after the server rejected it.
+    pub async fn invalidate(&self, auth: &Auth) {
+        self.tokens.lock().await.remove(&cache_key(auth));
+    }
+
+    /// Adds what is only known once the request is built: its signature, or the
+    /// answer to the last digest challenge of the server.
+    pub async fn sign(&self, auth: &Auth, request: &mut reqwest::Request) -> Result<(), String> {
+        let Auth::Digest(credentials) = auth else {
+            return sign(auth, request);
+        };
+        let mut challenges = self.challenges.lock().await;
+        let Some(challenge) = challenges.get_mut(&digest_key(credentials, request.url())) else {
+            return Ok(());
+        };
+        challenge.count += 1;
+        let url = request.url();
+        let uri = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let cnonce = hex(&rand::random::<[u8; 16]>());
+        let method = request.method().as_str();
+        let authorization = digest_authorization(credentials, challenge, method, &uri, &cnonce);
+        insert_header(request.headers_mut(), "authorization", &authorization)
+    }
+
+    /// Records the digest challenge of a `401` response, returning whether there is
+    /// one to answer by sending the request again.
+    pub async fn challenge(
+        &self,
+        auth: &Auth,
+        url: &reqwest::Url,
+        headers: &HeaderMap,
+    ) -> Result<bool, String> {
+        let Auth::Digest(credentials) = auth else {
+            return Ok(false);
+        };
+        let mut error = None;
+        let challenges = headers
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(parse_challenges)
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("digest"));
+        for (_, params) in challenges {
+            match DigestChallenge::from_params(&params) {
+                Ok(challenge) => {
+                    debug!("Answering the digest challenge of realm `{}`", challenge.realm);
+                    self.challenges.lock().await.insert(digest_key(credentials, url), challenge);
+                    return Ok(true);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        error.map_or(Ok(false), Err)
+    }
+}
+
+/// Identifies the token of a client: the endpoint, the client, the user and the scopes.
+fn cache_key(auth: &Auth) -> String {
+    match auth {
+        Auth::OAuth2ClientCredentials(client) => {
+            format!("{} {} {}", client.token_url, client.client_id, client.scopes.join(" "))
+        }
+        Auth::OAuth2Password { client, username, .. } => format!(
+            "{} {} {} {}",
+            client.token_url,
+            client.client_id,
+            client.scopes.join(" "),
+            username
+        ),
+        Auth::Basic(_)
+        | Auth::Bearer { .. }
+        | Auth::Digest(_)
+        | Auth::AwsSigV4(_)
+        | Auth::Hmac(_) => String::new(),
+    }
+}
+
+async fn request_token(
+    client: &reqwest::Client,
+    oauth2: &OAuth2Client,
+    grant: &[(&str, &str)],
+) -> Result<Token, Box<dyn std::error::Error + Send + Sync>> {
+    let scope = oauth2.scopes.join(" ");
+    let mut form: Vec<(&str, &str)> = grant.to_vec();
+    form.push(("client_id", &oauth2.client_id));
+    if let Some(secret) = &oauth2.client_secret {
+        form.push(("client_secret", secret));
+    }
+    if !scope.is_empty() {
+        form.push(("scope", &scope));
+    }
+
+    info!("Requesting an OAuth2 token from {}...", oauth2.token_url);
+    let response = client.post(&oauth2.token_url).form(&form).send().await?;
+    let status = response.status();
+    let text = response.text().await?;
+    if !status.is_success() {
+        return Err(format!(
+            "token request to {} failed with status {}: {}",
+            oauth2.token_url, status, text
+        )
+        .into());
+    }
+    let token: TokenResponse = serde_json::from_str(&text)
+        .map_err(|e| format!("invalid token response from {}: {}", oauth2.token_url, e))?;
+    debug!("Token received, expires in {:?} second(s)", token.expires_in);
+    Ok(Token {
+        access_token: token.access_token,
+        expires_at: token
+            .expires_in
+            .map(|seconds| Instant::now() + Duration::from_secs(seconds)),
+        refresh_token: token.refresh_token,
+    })
+}
+
+/// Digest challenges apply to a user on a server.
+fn digest_key(credentials: &BasicAuth, url: &reqwest::Url) -> String {
+    format!("{} {}", credentials.username, url.origin().ascii_serialization())
+}
+
+fn digest_authorization(
+    credentials: &BasicAuth,
+    challenge: &DigestChallenge,
+    method: &str,
+    uri: &str,
+    cnonce: &str,
+) -> String {
+    let hash = digest_hash(&challenge.algorithm).expect("checked when the challenge was received");
+    let nonce = &challenge.nonce;
+    let (username, password) = (&credentials.username, &credentials.password);
+    let mut ha1 = hash(&format!("{}:{}:{}", username, challenge.realm, password));
+    if challenge.algorithm.to_ascii_uppercase().ends_with("-SESS") {
+        ha1 = hash(&format!("{}:{}:{}", ha1, nonce, cnonce));
+    }
+    let ha2 = hash(&format!("{}:{}", method, uri));
+    let count = format!("{:08x}", challenge.count);
+    let response = match challenge.qop {
+        Some(qop) => hash(&format!("{}:{}:{}:{}:{}:{}", ha1, nonce, count, cnonce, qop, ha2)),
+        None => hash(&format!("{}:{}:{}", ha1, nonce, ha2)),
+    };
+
+    let mut authorization = format!(
+        "Digest username={}, realm={}, nonce={}, uri={}, algorithm={}, response=\"{}\"",
+        quote(&credentials.username),
+        quote(&challenge.realm),
+        quote(nonce),
+        quote(uri),
+        challenge.algorithm,
+        response
+    );
+    if let Some(opaque) = &challenge.opaque {
+        authorization.push_str(&format!(", opaque={}", quote(opaque)));
+    }
+    if let Some(qop) = challenge.qop {
+        authorization.push_str(&format!(", qop={}, nc={}, cnonce=\"{}\"", qop, count, cnonce));
+    }
+    authorization
+}
+
+fn digest_hash(algorithm: &str) -> Result<fn(&str) -> String, String> {
+    let name = algorithm.to_ascii_uppercase();
+    match name.strip_suffix("-SESS").unwrap_or(&name) {
+        "MD5" => Ok(|s| hex(&Md5::digest(s.as_bytes()))),
+        "SHA-256" => Ok(|s| hex(&Sha256::digest(s.as_bytes()))),
+        _ => Err(format!("unsupported digest algorithm `{}`", algorithm)),
+    }
+}
+
+/// Splits a `WWW-Authenticate` header into its challenges, each with its scheme and
+/// parameters.
+fn parse_challenges(header: &str) -> Vec<(String, HashMap<String, String>)> {
+    let mut challenges: Vec<(String, HashMap<String, String>)> = Vec::new();
+    let mut rest = header;
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        if rest.is_empty() {
+            return challenges;
+        }
+        let end = rest.find([' ', '\t', ',', '=']).unwrap_or(rest.len());
+        let token = &rest[..end];
+        let after = rest[end..].trim_start_matches([' ', '\t']);
+        match (after.strip_prefix('='), challenges.last_mut()) {
+            (Some(value), Some((_, params))) => {
+                let (value, remainder) = parse_param_value(value.trim_start());
+                params.insert(token.to_ascii_lowercase(), value);
+                rest = remainder;
+            }
+            _ => {
+                challenges.push((token.to_string(), HashMap::new()));
+                rest = after;
+            }
+        }
+    }
+}
+
+/// Reads a token or a quoted string, returning it with what follows.
+fn parse_param_value(s: &str) -> (String, &str) {
+    let Some(quoted) = s.strip_prefix('"') else {
+        let end = s.find([',', ' ', '\t']).unwrap_or(s.len());
+        return (s[..end].to_string(), &s[end..]);
+    };
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => value.extend(chars.next().map(|(_, escaped)| escaped)),
+            '"' => return (value, &quoted[i + 1..]),
+            c => value.push(c),
+        }
+    }
+    (value, "")
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Signs a built request when `auth` is a signing mode. The signature covers the
+/// method, URL, headers and body as they will be sent.
+fn sign(auth: &Auth, request: &mut reqwest::Request) -> Result<(), String> {
+    match auth {
+        Auth::AwsSigV4(aws) => sign_aws(aws, request, Utc::now()),
+        Auth::Hmac(hmac) => sign_hmac(hmac, request, Utc::now()),
+        _ => Ok(()),
+    }
+}
+
+fn sign_aws(
+    aws: &AwsSigV4,
+    request: &mut reqwest::Request,
+    now: DateTime<Utc>,
+) -> Result<(), String> {
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    // Streamed bodies (files) cannot be hashed before they are sent
+    let payload_hash = match request.body().map(|body| body.as_bytes()) {
+        None => hex(&Sha256::digest(b"")),
+        Some(Some(bytes)) => hex(&Sha256::digest(bytes)),
+        Some(None) => "UNSIGNED-PAYLOAD".to_string(),
+    };
+    let headers = request.headers_mut();
+    insert_header(headers, "x-amz-date", &amz_date)?;
+    if aws.service == "s3" {
+        insert_header(headers, "x-amz-content-sha256", &payload_hash)?;
+    }
+    if let Some(token) = &aws.session_token {
+        insert_header(headers, "x-amz-security-token", token)?;
+    }
+    let authorization = sigv4_authorization(
+        aws,
+        request.method().as_str(),
+        request.url(),
+        request.headers(),
+        &payload_hash,
+        &amz_date,
+    );
+    insert_header(request.headers_mut(), "authorization", &authorization)
+}
+
+/// Headers left out of AWS signatures, as proxies may change them.
+const UNSIGNED_HEADERS: [&str; 3] = ["user-agent", "expect", "x-amzn-trace-id"];
+
+/// Computes the `Authorization` header of AWS Signature Version 4, signing the
+/// headers of the request plus `Host`.
+fn sigv4_authorization(
+    aws: &AwsSigV4,
+    method: &str,
+    url: &reqwest::Url,
+    headers: &HeaderMap,
+    payload_hash: &str,
+    amz_date: &str,
+) -> String {
+    // S3 signs the path as sent, other services sign it encoded once more
+    let path = url
+        .path()
+        .split('/')
+        .map(|segment| {
+            let encoded = uri_encode(&percent_decode(segment));
+            if aws.service == "s3" {
+                encoded
+            } else {
+                uri_encode(encoded.as_bytes())
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let mut query: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(key, value)| (uri_encode(key.as_bytes()), uri_encode(value.as_bytes())))
+        .collect();
+    query.sort();
+    let query: Vec<String> = query
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+
+    let mut canonical_headers: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (name, value) in headers {
+        if UNSIGNED_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        let value = String::from_utf8_lossy(value.as_bytes());
+        let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+        canonical_headers
+            .entry(name.as_str().to_string())
+            .or_default()
+            .push(value);
+    }
+    canonical_headers
+        .entry("host".to_string())
+        .or_insert_with(|| {
+            let host = url.host_str().unwrap_or_default();
+            vec![
+                url.port()
+                    .map_or(host.to_string(), |port| format!("{}:{}", host, port)),
+            ]
+        });
+    let signed_headers = canonical_headers
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = canonical_headers
+        .iter()
+        .map(|(name, values)| format!("{}:{}\n", name, values.join(",")))
+        .collect();
+
+    let canonical_request = [
+        method,
+        &path,
+        &query.join("&"),
+        &canonical_headers,
+        &signed_headers,
+        payload_hash,
+    ]
+    .join("\n");
+    debug!("AWS canonical request:\n{}", canonical_request);
+
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, aws.region, aws.service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = format!("AWS4{}", aws.secret_access_key);
+    let key = [date, &aws.region, &aws.service, "aws4_request"]
+        .iter()
+        .fold(key.into_bytes(), |key, part| {
+            hmac(HmacAlgorithm::Sha256, &key, part.as_bytes())
+        });
+    let signature = hex(&hmac(
+        HmacAlgorithm::Sha256,
+        &key,
+        string_to_sign.as_bytes(),
+    ));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        aws.access_key_id, scope, signed_headers, signature
+    )
+}
+
+fn sign_hmac(
+    hmac_auth: &HmacAuth,
+    request: &mut reqwest::Request,
+    now: DateTime<Utc>,
+) -> Result<(), String> {
+    let timestamp = now.timestamp().to_string();
+    if let Some(name) = &hmac_auth.timestamp_header {
+        insert_header(request.headers_mut(), name, &timestamp)?;
+    }
+    let message = string_to_sign(&hmac_auth.template, request, &timestamp)?;
+    let signature = hmac(hmac_auth.algorithm, hmac_auth.secret.as_bytes(), &message);
+    let signature = match hmac_auth.encoding {
+        SignatureEncoding::Hex => hex(&signature),
+        SignatureEncoding::Base64 => BASE64.encode(signature),
+    };
+    let value = format!("{}{}", hmac_auth.prefix, signature);
+    insert_header(request.headers_mut(), &hmac_auth.header, &value)
+}
+
+/// Fills the `{...}` placeholders of an HMAC template with the values of a request.
+fn string_to_sign(
+    template: &str,
+    request: &reqwest::Request,
+    timestamp: &str,
+) -> Result<Vec<u8>, String> {
+    let body = match request.body() {
+        None => &[][..],
+        Some(body) => body
+            .as_bytes()
+            .ok_or("requests with files cannot be signed with `hmac`")?,
+    };
+    let url = request.url();
+    let mut message = Vec::new();
+    for part in parse_signing_template(template)? {
+        match part {
+            TemplatePart::Text(text) => message.extend(text.as_bytes()),
+            TemplatePart::Placeholder("method") => {
+                message.extend(request.method().as_str().as_bytes())
+            }
+            TemplatePart::Placeholder("url") => message.extend(url.as_str().as_bytes()),
+            TemplatePart::Placeholder("path") => {
+                message.extend(url.path().as_bytes());
+                if let Some(query) = url.query() {
+                    message.extend(format!("?{}", query).as_bytes());
+                }
+            }
+            TemplatePart::Placeholder("query") => {
+                message.extend(url.query().unwrap_or_default().as_bytes())
+            }
+            TemplatePart::Placeholder("host") => {
+                message.extend(url.host_str().unwrap_or_default().as_bytes())
+            }
+            TemplatePart::Placeholder("body") => message.extend(body),
+            TemplatePart::Placeholder("body_sha256") => {
+                message.extend(hex(&Sha256::digest(body)).as_bytes())
+            }
+            TemplatePart::Placeholder("timestamp") => message.extend(timestamp.as_bytes()),
+            TemplatePart::Placeholder(_) => {}
+            TemplatePart::Header(name) => {
+                let values: Vec<&[u8]> = request
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .map(|value| value.as_bytes())
+                    .collect();
+                message.extend(values.join(&b", "[..]));
+            }
+        }
+    }
+    Ok(message)
+}
+
+pub enum TemplatePart<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+    Header(&'a str),
+}
+
+const PLACEHOLDERS: [&str; 8] = [
+    "method",
+    "url",
+    "path",
+    "query",
+    "host",
+    "body",
+    "body_sha256",
+    "timestamp",
+];
+
+/// Splits an HMAC template into text and placeholders, rejecting unknown ones.
+pub fn parse_signing_template(template: &str) -> Result<Vec<TemplatePart<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| format!("unterminated `{{` in HMAC template `{}`", template))?;
+        parts.push(TemplatePart::Text(&rest[..start]));
+        let placeholder = &rest[start + 1..end];
+        match placeholder.strip_prefix("header:") {
+            Some(name) => parts.push(TemplatePart::Header(name)),
+            None if PLACEHOLDERS.contains(&placeholder) => {
+                parts.push(TemplatePart::Placeholder(placeholder))
+            }
+            None => {
+                return Err(format!(
+                    "unknown placeholder `{{{}}}` in HMAC template `{}`",
+                    placeholder, template
+                ));
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    parts.push(TemplatePart::Text(rest));
+    Ok(parts)
+}
+
+fn hmac(algorithm: HmacAlgorithm, key: &[u8], data: &[u8]) -> Vec<u8> {
+    fn mac<M: Mac + hmac::digest::KeyInit>(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+    match algorithm {
+        HmacAlgorithm::Sha256 => mac::<Hmac<Sha256>>(key, data),
+        HmacAlgorithm::Sha384 => mac::<Hmac<Sha384>>(key, data),
+        HmacAlgorithm::Sha512 => mac::<Hmac<Sha512>>(key, data),
+    }
+}
+
+fn insert_header(headers: &mut HeaderMap, name: &str, value: &str) -> Result<(), String> {
+    let name =
+        HeaderName::try_from(name).map_err(|e| format!("invalid header `{}`: {}", name, e))?;
+    let value =
+        HeaderValue::from_str(value).map_err(|e| format!("invalid `{}` value: {}", name, e))?;
+    headers.insert(name, value);
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encodes everything but unreserved characters, as AWS expects.
+fn uri_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = match (bytes[i], bytes.get(i + 1..i + 3)) {
+            (b'%', Some(digits)) => std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok()),
+            _ => None,
+        };
+        match byte {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_freshness() {
+        let token = |expires_in: Option<u64>| Token {
+            access_token: "abc".to_string(),
+            expires_at: expires_in.map(|seconds| Instant::now() + Duration::from_secs(seconds)),
+            refresh_token: None,
+        };
+
+        assert!(token(None).is_fresh());
+        assert!(token(Some(3600)).is_fresh());
+        assert!(!token(Some(10)).is_fresh());
+    }
+
+    #[tokio::test]
+    async fn test_static_credentials() {
+        let tokens = Tokens::default();
+        let client = reqwest::Client::new();
+        let basic: Auth = serde_yaml::from_str("{ type: basic, username: user, password: pass }").unwrap();
+        let bearer: Auth = serde_yaml::from_str("{ type: bearer, token: abc }").unwrap();
+
+        let authorization = |auth| tokens.authorization(auth, &client);
+        assert_eq!(
+            authorization(&basic).await.unwrap().unwrap(),
+            "Basic dXNlcjpwYXNz"
+        );
+        assert_eq!(authorization(&bearer).await.unwrap().unwrap(), "Bearer abc");
+    }
+
+    #[test]
+    fn test_parse_challenges() {
+        let header = r#"Basic realm="simple", Digest realm="a \"b\", c", qop="auth,auth-int", nonce=abc"#;
+        let challenges = parse_challenges(header);
+
+        assert_eq!(challenges.len(), 2);
+        let simple = HashMap::from([("realm".to_string(), "simple".to_string())]);
+        assert_eq!(challenges[0], ("Basic".to_string(), simple));
+        assert_eq!(challenges[1].0, "Digest");
+        assert_eq!(challenges[1].1["realm"], "a \"b\", c");
+        assert_eq!(challenges[1].1["nonce"], "abc");
+        let challenge = DigestChallenge::from_params(&challenges[1].1).unwrap();
+        assert_eq!((challenge.algorithm.as_str(), challenge.qop), ("MD5", Some("auth")));
+
+        let unsupported =
+            HashMap::from([("nonce".into(), "n".into()), ("algorithm".into(), "SHA-512".into())]);
+        assert!(DigestChallenge::from_params(&unsupported).is_err());
+    }
+
+    // Examples of RFC 2617 and RFC 7616
+    #[test]
+    fn test_digest_authorization() {
+        let credentials = |password: &str| BasicAuth {
+            username: "Mufasa".to_string(),
+            password: password.to_string(),
+        };
+        let mut challenge = DigestChallenge {
+            realm: "testrealm@host.com".to_string(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_string(),
+            opaque: Some("5ccc069c403ebaf9f0171e9517f40e41".to_string()),
+            algorithm: "MD5".to_string(),
+            qop: Some("auth"),
+            count: 1,
+        };
+        let authorization = |challenge: &DigestChallenge, password: &str, cnonce: &str| {
+            digest_authorization(&credentials(password), challenge, "GET", "/dir/index.html", cnonce)
+        };
+
+        assert_eq!(
+            authorization(&challenge, "Circle Of Life", "0a4f113b"),
+            "Digest username=\"Mufasa\", realm=\"testrealm@host.com\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", uri=\"/dir/index.html\", algorithm=MD5, \
+             response=\"6629fae49393a05397450978507c4ef1\", opaque=\"5ccc069c403ebaf9f0171e9517f40e41\", \
+             qop=auth, nc=00000001, cnonce=\"0a4f113b\""
+        );
+
+        challenge.realm = "http-auth@example.org".to_string();
+        challenge.nonce = "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v".to_string();
+        let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+        assert!(
+            authorization(&challenge, "Circle of Life", cnonce)
+                .contains("response=\"8ca523f5e9506fed4657c9700eebdbec\"")
+        );
+        challenge.algorithm = "SHA-256".to_string();
+        assert!(
+            authorization(&challenge, "Circle of Life", cnonce).contains(
+                "response=\"753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1\""
+            )
+        );
+    }
+
+    // Cases of the AWS Signature Version 4 test suite
+    #[test]
+    fn test_sigv4() {
+        let aws: AwsSigV4 = serde_yaml::from_str(
+            "{ region: us-east-1, service: service, access_key_id: AKIDEXAMPLE, \
+             secret_access_key: wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY }",
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-date", HeaderValue::from_static("20150830T123600Z"));
+        let empty = hex(&Sha256::digest(b""));
+        let sign = |url: &str| {
+            let url = reqwest::Url::parse(url).unwrap();
+            sigv4_authorization(&aws, "GET", &url, &headers, &empty, "20150830T123600Z")
+        };
+
+        assert_eq!(
+            sign("https://example.amazonaws.com/"),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+        assert!(
+            sign("https://example.amazonaws.com/?Param2=value2&Param1=value1").ends_with(
+                "Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+            )
+        );
+    }
+
+    #[test]
+    fn test_hmac() {
+        let client = reqwest::Client::new();
+        let mut request = client
+            .post("http://api.example.com/orders?page=2")
+            .header("X-Key", "k1")
+            .body("{}")
+            .build()
+            .unwrap();
+        let hmac_auth: Auth = serde_yaml::from_str(
+            "{ type: hmac, secret: s3cr3t, template: \"{method} {path} {header:x-key} {timestamp} {body}\", \
+             prefix: sha256=, timestamp_header: X-Timestamp }",
+        )
+        .unwrap();
+        let Auth::Hmac(hmac_auth) = hmac_auth else {
+            unreachable!()
+        };
+        let now = DateTime::from_timestamp(1700000000, 0).unwrap();
+
+        assert_eq!(
+            string_to_sign(&hmac_auth.template, &request, "1700000000").unwrap(),
+            b"POST /orders?page=2 k1 1700000000 {}"
+        );
+        sign_hmac(&hmac_auth, &mut request, now).unwrap();
+        let expected = hmac(
+            HmacAlgorithm::Sha256,
+            b"s3cr3t",
+            b"POST /orders?page=2 k1 1700000000 {}",
+        );
+        assert_eq!(
+            request.headers()["x-signature"],
+            format!("sha256={}", hex(&expected))
+        );
+        assert_eq!(request.headers()["x-timestamp"], "1700000000");
+        assert!(string_to_sign("{nope}", &request, "0").is_err());
+        assert_eq!(
+            hex(&hmac(
+                HmacAlgorithm::Sha256,
+                b"key",
+                b"The quick brown fox jumps over the lazy dog"
+            )),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::time::Duration;
+
+use reqwest::redirect::Policy;
+use reqwest::tls::{Certificate, Identity, Version};
+
+use crate::auth::Tokens;
+use crate::resolve::render;
+use crate::types::{Task, Tls, TlsVersion};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Task settings that can only be configured on a `reqwest::Client`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+struct ClientSettings {
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    tls: Tls,
+    proxy: Option<String>,
+    no_proxy: Option<Vec<String>>,
+    max_redirects: Option<usize>,
+    /// Host and port of the URL, connected to in place of `tls.server_name`
+    address: Option<String>,
+}
+
+impl ClientSettings {
+    fn of(task: &Task) -> Self {
+        ClientSettings {
+            connect_timeout: task.connect_timeout,
+            read_timeout: task.read_timeout,
+            tls: task.tls.clone().unwrap_or_default(),
+            proxy: task.proxy.clone(),
+            no_proxy: task.no_proxy.clone(),
+            max_redirects: task.max_redirects,
+            address: task
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.server_name.as_ref())
+                .and_then(|_| address(&task.url)),
+        }
+    }
+
+    fn builder(&self) -> Result<reqwest::ClientBuilder, BoxError> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(proxy) = &self.proxy {
+            // Rendered here so credentials can come from the environment
+            let url = render(proxy, &HashMap::new())?;
+            let mut proxy = reqwest::Proxy::all(&url)
+                .map_err(|e| format!("invalid `proxy` {}: {}", proxy, cause(&e)))?;
+            if let Some(hosts) = &self.no_proxy {
+                proxy = proxy.no_proxy(reqwest::NoProxy::from_string(&hosts.join(",")));
+            }
+            builder = builder.proxy(proxy);
+        } else if let Some(hosts) = &self.no_proxy {
+            for proxy in system_proxies(hosts)? {
+                builder = builder.proxy(proxy);
+            }
+        }
+        if let Some(max) = self.max_redirects {
+            builder = builder.redirect(match max {
+                0 => Policy::none(),
+                max => Policy::limited(max),
+            });
+        }
+
+        let tls = &self.tls;
+        if let Some(path) = &tls.ca_cert {
+            let pem = read(path, "ca_cert")?;
+            let certificates = Certificate::from_pem_bundle(&pem)
+                .map_err(|e| format!("invalid `ca_cert` {}: {}", path, cause(&e)))?;
+            if certificates.is_empty() {
+                return Err(format!("no certificate found in `ca_cert` {}", path).into());
+            }
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let Some(path) = &tls.client_cert {
+            let certificate = read(path, "client_cert")?;
+            let identity = match &tls.client_key {
+                Some(key_path) => {
+                    Identity::from_pkcs8_pem(&certificate, &read(key_path, "client_key")?)
+                }
+                None => {
+                    // The password may come from the environment, which is loaded by now
+                    let password = tls.client_cert_password.as_deref().unwrap_or_default();
+                    Identity::from_pkcs12_der(&certificate, &render(password, &HashMap::new())?)
+                }
+            }
+            .map_err(|e| format!("invalid `client_cert` {}: {}", path, cause(&e)))?;
+            builder = builder.identity(identity);
+        }
+        if tls.insecure_skip_verify == Some(true) {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(version) = tls.min_version {
+            builder = builder.min_tls_version(match version {
+                TlsVersion::V1_0 => Version::TLS_1_0,
+                TlsVersion::V1_1 => Version::TLS_1_1,
+                TlsVersion::V1_2 => Version::TLS_1_2,
+            });
+        }
+        Ok(builder)
+    }
+
+    async fn build(&self) -> Result<reqwest::Client, BoxError> {
+        let mut builder = self.builder()?;
+        if let (Some(server_name), Some(address)) = (&self.tls.server_name, &self.address) {
+            let addresses: Vec<_> = tokio::net::lookup_host(address)
+                .await
+                .map_err(|e| format!("cannot resolve {}: {}", address, e))?
+                .collect();
+            builder = builder.resolve_to_addrs(server_name, &addresses);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// The host and port of a URL, if they do not depend on responses.
+fn address(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(&render(url, &HashMap::new()).ok()?).ok()?;
+    // Unresolved references are kept as written, and parse as part of a host name
+    let host = url.host_str().filter(|host| !host.contains("{{"))?;
+    Some(format!("{}:{}", host, url.port_or_known_default()?))
+}
+
+/// The proxies of the `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY` environment variables,
+/// skipping `no_proxy` as well as the `NO_PROXY` hosts. reqwest stops reading the
+/// environment once a proxy is set, so they are rebuilt here.
+fn system_proxies(no_proxy: &[String]) -> Result<Vec<reqwest::Proxy>, BoxError> {
+    let var = |name: &str| {
+        [name.to_string(), name.to_lowercase()]
+            .into_iter()
+            .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+    };
+    let hosts: Vec<String> = no_proxy.iter().cloned().chain(var("NO_PROXY")).collect();
+    let no_proxy = reqwest::NoProxy::from_string(&hosts.join(","));
+
+    let mut proxies = Vec::new();
+    for name in ["HTTP_PROXY", "HTTPS_PROXY", "ALL_PROXY"] {
+        let Some(url) = var(name) else {
+            continue;
+        };
+        let proxy = match name {
+            "HTTP_PROXY" => reqwest::Proxy::http(url),
+            "HTTPS_PROXY" => reqwest::Proxy::https(url),
+            _ => reqwest::Proxy::all(url),
+        }
+        .map_err(|e| format!("invalid `{}`: {}", name, cause(&e)))?;
+        proxies.push(proxy.no_proxy(no_proxy.clone()));
+    }
+    Ok(proxies)
+}
+
+/// The underlying error of a TLS setup error, which says more than "builder error".
+pub fn cause(e: &reqwest::Error) -> String {
+    let mut cause: &dyn std::error::Error = e;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+fn read(path: &str, field: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("cannot read `{}` {}: {}", field, path, e))
+}
+
+/// One HTTP client per distinct set of client settings used in a workflow, whether
+/// set on tasks, in `defaults` or in client profiles, so tasks sharing settings also
+/// share connections, and the OAuth2 tokens of the run.
+pub struct Clients {
+    clients: HashMap<ClientSettings, reqwest::Client>,
+    pub tokens: Tokens,
+}
+
+impl Clients {
+    pub async fn build(tasks: &[Task]) -> Result<Self, BoxError> {
+        let mut clients = HashMap::new();
+        clients.insert(ClientSettings::default(), reqwest::Client::new());
+        for task in tasks {
+            let settings = ClientSettings::of(task);
+            if settings.tls.server_name.is_some() && settings.address.is_none() {
+                return Err(format!(
+                    "Task `{}`: `tls.server_name` needs a URL whose host is known before the run",
+                    task.name
+                )
+                .into());
+            }
+            if let Entry::Vacant(entry) = clients.entry(settings) {
+                let client = entry.key().build().await?;
+                entry.insert(client);
+            }
+        }
+        Ok(Clients {
+            clients,
+            tokens: Tokens::default(),
+        })
+    }
+
+    /// The client built for the settings of a task. Sending through any other client
+    /// would silently drop its proxy, TLS, timeout and redirect settings.
+    pub fn for_task(&self, task: &Task) -> Result<reqwest::Client, String> {
+        self.clients
+            .get(&ClientSettings::of(task))
+            .cloned()
+            .ok_or_else(|| format!("Task `{}`: no client was built for its settings", task.name))
+    }
+}
+
+/// Sends a request to the `tls.server_name` of its task instead of the host of its
+/// URL. The client of the task connects that name to the addresses of the URL host.
+pub fn pin_server_name(task: &Task, request: &mut reqwest::Request) -> Result<(), BoxError> {
+    let Some(server_name) = task.tls.as_ref().and_then(|tls| tls.server_name.as_deref()) else {
+        return Ok(());
+    };
+    request
+        .url_mut()
+        .set_host(Some(server_name))
+        .map_err(|e| format!("invalid `tls.server_name` {}: {}", server_name, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address() {
+        assert_eq!(address("https://10.0.0.12/api").as_deref(), Some("10.0.0.12:443"));
+        assert_eq!(address("http://localhost:8080/{{item}}").as_deref(), Some("localhost:8080"));
+        assert_eq!(address("https://{{login.json.host}}/api"), None);
+        assert_eq!(address("not a url"), None);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_settings_are_an_error() {
+        let task: Task = serde_yaml::from_str(
+            "{ name: t, type: http, method: GET, url: 'http://localhost/', \
+             proxy: 'http://proxy:3128' }",
+        )
+        .unwrap();
+        let clients = Clients::build(&[]).await.unwrap();
+
+        let error = clients.for_task(&task).unwrap_err();
+        assert_eq!(error, "Task `t`: no client was built for its settings");
+    }
+
+    async fn client_for(yaml: &str) -> reqwest::Client {
+        let task: Task = serde_yaml::from_str(yaml).unwrap();
+        Clients::build(std::slice::from_ref(&task)).await.unwrap().for_task(&task).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout_is_applied() {
+        // Accepts connections but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let client = client_for(&format!(
+            "{{ name: t, type: http, method: GET, url: '{}', read_timeout: 100ms }}",
+            url
+        ))
+        .await;
+
+        let error = client.get(&url).send().await.unwrap_err();
+        assert!(error.is_timeout(), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout_is_applied() {
+        // With its only backlog slot taken, the listener drops further connection attempts
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(0).unwrap();
+        let address = listener.local_addr().unwrap();
+        let _queued = std::net::TcpStream::connect(address).unwrap();
+        let url = format!("http://{}/", address);
+        let client = client_for(&format!(
+            "{{ name: t, type: http, method: GET, url: '{}', connect_timeout: 100ms }}",
+            url
+        ))
+        .await;
+
+        let error = client.get(&url).send().await.unwrap_err();
+        assert!(error.is_connect() && error.is_timeout(), "{:?}", error);
+    }
+}
@@ -1,86 +1,115 @@
 use log::debug;
-use crate::types::Task;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
 
-pub trait EnvResolver {
-    fn resolve_env_vars(&mut self);
+/// Variables read from `.env` files, consulted when the process environment lacks a name.
+static DOTENV: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Returns an environment variable, falling back to the loaded `.env` files.
+pub fn var(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .or_else(|| DOTENV.get().and_then(|vars| vars.get(name).cloned()))
 }
 
-impl EnvResolver for Task {
-    fn resolve_env_vars(&mut self) {
-        // Resolve environment variables in URL
-        let mut url = self.url.clone();
-        let mut start = 0;
-        while let Some(pos) = url[start..].find("{{env.") {
-            let start_pos = start + pos;
-            if let Some(end_pos) = url[start_pos..].find("}}") {
-                let end_pos = start_pos + end_pos + 2; // +2 for the closing }}
-                let var = url[start_pos + 6..end_pos - 2].to_string(); // 6 is length of "{{env."
-                let value = std::env::var(&var).unwrap_or_else(|_| {
-                    debug!("Environment variable {} not found", var);
-                    "".to_string()
-                });
-                url = url[..start_pos].to_string() + &value + &url[end_pos..];
-                start = start_pos + value.len();
-            } else {
-                break;
-            }
-        }
-        self.url = url;
+/// Loads `.env` files, later files overriding earlier ones. Can only be done once.
+pub fn load_files(paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut vars = HashMap::new();
+    for path in paths {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let parsed = parse_dotenv(&text).map_err(|e| format!("{}: {}", path, e))?;
+        debug!("Loaded {} variable(s) from {}", parsed.len(), path);
+        vars.extend(parsed);
+    }
+    DOTENV
+        .set(vars)
+        .map_err(|_| "environment files were already loaded".into())
+}
 
-        // Resolve environment variables in auth
-        if let Some(auth) = &mut self.auth {
-            if auth.username.starts_with("{{env.") && auth.username.ends_with("}}") {
-                let var = auth
-                    .username
-                    .trim_matches(|c| c == '{' || c == '}')
-                    .strip_prefix("env.")
-                    .unwrap();
-                auth.username = std::env::var(var).unwrap_or_else(|_| auth.username.clone());
-            }
-            if auth.password.starts_with("{{env.") && auth.password.ends_with("}}") {
-                let var = auth
-                    .password
-                    .trim_matches(|c| c == '{' || c == '}')
-                    .strip_prefix("env.")
-                    .unwrap();
-                auth.password = std::env::var(var).unwrap_or_else(|_| auth.password.clone());
-            }
+/// Parses `KEY=value` lines. Blank lines and `#` comments are skipped, `export` is
+/// allowed, and values can be single-quoted (literal) or double-quoted (with `\n`,
+/// `\t`, `\"` and `\\` escapes).
+pub fn parse_dotenv(text: &str) -> Result<HashMap<String, String>, String> {
+    let mut vars = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
-
-        // Resolve environment variables in headers
-        for (_, value) in self.headers.iter_mut() {
-            if value.starts_with("{{env.") && value.ends_with("}}") {
-                let var = value
-                    .trim_matches(|c| c == '{' || c == '}')
-                    .strip_prefix("env.")
-                    .unwrap();
-                *value = std::env::var(var).unwrap_or_else(|_| value.clone());
-            }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected KEY=value", number + 1));
+        };
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("line {}: invalid variable name `{}`", number + 1, key));
         }
-
-        // Resolve environment variables in expect conditions
-        for expect in &mut self.expect {
-            match expect {
-                crate::types::Expect::Raw { contains } => {
-                    if contains.starts_with("{{env.") && contains.ends_with("}}") {
-                        let var = contains
-                            .trim_matches(|c| c == '{' || c == '}')
-                            .strip_prefix("env.")
-                            .unwrap();
-                        *contains = std::env::var(var).unwrap_or_else(|_| contains.clone());
-                    }
-                }
-                crate::types::Expect::JsonPath { value, .. } => {
-                    if value.starts_with("{{env.") && value.ends_with("}}") {
-                        let var = value
-                            .trim_matches(|c| c == '{' || c == '}')
-                            .strip_prefix("env.")
-                            .unwrap();
-                        *value = std::env::var(var).unwrap_or_else(|_| value.clone());
-                    }
-                }
-                crate::types::Expect::Status { .. } => {} // No environment variables in status codes
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            quoted
+                .strip_suffix('\'')
+                .ok_or_else(|| format!("line {}: unterminated quote", number + 1))?
+                .to_string()
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            let quoted = quoted
+                .strip_suffix('"')
+                .ok_or_else(|| format!("line {}: unterminated quote", number + 1))?;
+            unescape(quoted)
+        } else {
+            // Unquoted values end at an inline comment
+            match value.find(" #") {
+                Some(comment) => value[..comment].trim_end().to_string(),
+                None => value.to_string(),
             }
+        };
+        vars.insert(key.to_string(), value);
+    }
+    Ok(vars)
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
         }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dotenv() {
+        let vars = parse_dotenv(
+            r#"
+# comment
+API_HOST=api.example.com
+export TOKEN = abc123 # inline comment
+SINGLE='a #literal $value'
+DOUBLE="line\none \"quoted\""
+EMPTY=
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(vars["API_HOST"], "api.example.com");
+        assert_eq!(vars["TOKEN"], "abc123");
+        assert_eq!(vars["SINGLE"], "a #literal $value");
+        assert_eq!(vars["DOUBLE"], "line\none \"quoted\"");
+        assert_eq!(vars["EMPTY"], "");
+        assert_eq!(parse_dotenv("NOPE").unwrap_err(), "line 1: expected KEY=value");
+        assert!(parse_dotenv("A='open").is_err());
     }
-} 
\ No newline at end of file
+}
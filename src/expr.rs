@@ -0,0 +1,543 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Number, Value};
+use sha2::{Digest, Sha256};
+
+use crate::resolve::plain_string;
+
+/// A parsed `{{...}}` expression. A filter `value | name(args)` is the call
+/// `name(value, args)`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Reference(String),
+    Call { name: String, args: Vec<Expr> },
+    Binary { op: char, left: Box<Expr>, right: Box<Expr> },
+    Negate(Box<Expr>),
+}
+
+/// Built-in functions with their minimum and maximum number of arguments,
+/// the value on the left of a `|` counting as the first one.
+const FUNCTIONS: [(&str, usize, usize); 12] = [
+    ("uuid", 0, 0),
+    ("now", 0, 0),
+    ("format", 2, 2),
+    ("random_int", 2, 2),
+    ("base64_encode", 1, 1),
+    ("sha256", 1, 1),
+    ("url_encode", 1, 1),
+    ("upper", 1, 1),
+    ("lower", 1, 1),
+    ("default", 2, 2),
+    ("length", 1, 1),
+    ("json_encode", 1, 1),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(Value),
+    Path(String),
+    Punct(char),
+}
+
+impl Expr {
+    /// Returns the references used anywhere in the expression.
+    pub fn references(&self) -> Vec<&str> {
+        let mut found = Vec::new();
+        self.collect_references(&mut found);
+        found
+    }
+
+    /// Returns the references the expression cannot do without, leaving out those
+    /// given a fallback with `default`.
+    pub fn required_references(&self) -> Vec<&str> {
+        let mut found = Vec::new();
+        self.collect_required(&mut found);
+        found
+    }
+
+    fn collect_references<'a>(&'a self, found: &mut Vec<&'a str>) {
+        match self {
+            Expr::Literal(_) => {}
+            Expr::Reference(path) => found.push(path),
+            Expr::Call { args, .. } => args.iter().for_each(|arg| arg.collect_references(found)),
+            Expr::Binary { left, right, .. } => {
+                left.collect_references(found);
+                right.collect_references(found);
+            }
+            Expr::Negate(inner) => inner.collect_references(found),
+        }
+    }
+
+    fn collect_required<'a>(&'a self, found: &mut Vec<&'a str>) {
+        match self {
+            Expr::Call { name, args } if name == "default" => args[1].collect_required(found),
+            Expr::Call { args, .. } => args.iter().for_each(|arg| arg.collect_required(found)),
+            Expr::Binary { left, right, .. } => {
+                left.collect_required(found);
+                right.collect_required(found);
+            }
+            Expr::Negate(inner) => inner.collect_required(found),
+            Expr::Literal(_) | Expr::Reference(_) => self.collect_references(found),
+        }
+    }
+
+    /// Evaluates the expression, looking references up with `lookup`. Returns `None`
+    /// when a reference cannot be resolved, unless `default` provides a fallback.
+    pub fn evaluate(
+        &self,
+        lookup: &dyn Fn(&str) -> Result<Option<Value>, String>,
+    ) -> Result<Option<Value>, String> {
+        match self {
+            Expr::Literal(value) => Ok(Some(value.clone())),
+            Expr::Reference(path) => lookup(path),
+            Expr::Call { name, args } if name == "default" => {
+                match args[0].evaluate(lookup)? {
+                    Some(value) if !is_blank(&value) => Ok(Some(value)),
+                    _ => args[1].evaluate(lookup),
+                }
+            }
+            Expr::Call { name, args } => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    match arg.evaluate(lookup)? {
+                        Some(value) => values.push(value),
+                        None => return Ok(None),
+                    }
+                }
+                call(name, &values).map(Some)
+            }
+            Expr::Binary { op, left, right } => {
+                let (Some(left), Some(right)) = (left.evaluate(lookup)?, right.evaluate(lookup)?)
+                else {
+                    return Ok(None);
+                };
+                arithmetic(*op, &left, &right).map(Some)
+            }
+            Expr::Negate(inner) => match inner.evaluate(lookup)? {
+                Some(value) => arithmetic('-', &Value::from(0), &value).map(Some),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+/// Parses the contents of a `{{...}}`, e.g. `now() | format('%Y-%m-%d')` or
+/// `page.json.number + 1`.
+pub fn parse_expression(source: &str) -> Result<Expr, String> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.pipeline()?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(expr),
+        Some(token) => Err(format!("unexpected {}", describe(token))),
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if "(),|+-*/%".contains(c) {
+            tokens.push(Token::Punct(c));
+            i += 1;
+        } else if c == '\'' || c == '"' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err("unterminated string".to_string()),
+                    Some('\\') if i + 1 < chars.len() => {
+                        text.push(chars[i + 1]);
+                        i += 2;
+                    }
+                    Some(&q) if q == c => break,
+                    Some(&other) => {
+                        text.push(other);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Literal(Value::String(text)));
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = match text.parse::<i64>() {
+                Ok(n) => Value::from(n),
+                Err(_) => text
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(Number::from_f64)
+                    .map(Value::Number)
+                    .ok_or_else(|| format!("invalid number `{}`", text))?,
+            };
+            tokens.push(Token::Literal(number));
+        } else {
+            // A path runs until whitespace or punctuation; `[...]` is taken as is
+            let start = i;
+            let mut depth = 0;
+            while i < chars.len() {
+                match chars[i] {
+                    '[' => depth += 1,
+                    ']' if depth > 0 => depth -= 1,
+                    // A wildcard key, as in `users.json.data.*.id`
+                    '*' if chars[i - 1] == '.' => {}
+                    c if depth == 0 && (c.is_whitespace() || "(),|+*/%".contains(c)) => break,
+                    _ => {}
+                }
+                i += 1;
+            }
+            if depth > 0 {
+                return Err("unterminated `[`".to_string());
+            }
+            let path: String = chars[start..i].iter().collect();
+            tokens.push(match path.as_str() {
+                "true" => Token::Literal(Value::Bool(true)),
+                "false" => Token::Literal(Value::Bool(false)),
+                "null" => Token::Literal(Value::Null),
+                _ => Token::Path(path),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Literal(value) => format!("`{}`", value),
+        Token::Path(path) => format!("`{}`", path),
+        Token::Punct(c) => format!("`{}`", c),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn pipeline(&mut self) -> Result<Expr, String> {
+        let mut expr = self.sum()?;
+        while self.eat('|') {
+            let name = match self.tokens.get(self.pos) {
+                Some(Token::Path(name)) => name.clone(),
+                Some(token) => return Err(format!("expected a filter name, found {}", describe(token))),
+                None => return Err("expected a filter name after `|`".to_string()),
+            };
+            self.pos += 1;
+            let mut args = vec![expr];
+            if self.eat('(') {
+                args.extend(self.arguments()?);
+            }
+            expr = call_expr(name, args)?;
+        }
+        Ok(expr)
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        loop {
+            let op = if self.eat('+') {
+                '+'
+            } else if self.eat('-') {
+                '-'
+            } else {
+                return Ok(expr);
+            };
+            let right = self.product()?;
+            expr = Expr::Binary { op, left: Box::new(expr), right: Box::new(right) };
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Punct(c @ ('*' | '/' | '%'))) => *c,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            let right = self.unary()?;
+            expr = Expr::Binary { op, left: Box::new(expr), right: Box::new(right) };
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat('-') {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let Some(token) = self.tokens.get(self.pos).cloned() else {
+            return Err("unexpected end of expression".to_string());
+        };
+        self.pos += 1;
+        match token {
+            Token::Literal(value) => Ok(Expr::Literal(value)),
+            Token::Punct('(') => {
+                let expr = self.pipeline()?;
+                if !self.eat(')') {
+                    return Err("missing `)`".to_string());
+                }
+                Ok(expr)
+            }
+            Token::Path(name) if self.eat('(') => {
+                let args = self.arguments()?;
+                call_expr(name, args)
+            }
+            Token::Path(path) => Ok(Expr::Reference(path)),
+            Token::Punct(_) => Err(format!("unexpected {}", describe(&token))),
+        }
+    }
+
+    /// Parses call arguments after the opening `(`, up to and including `)`.
+    fn arguments(&mut self) -> Result<Vec<Expr>, String> {
+        let mut args = Vec::new();
+        if self.eat(')') {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.pipeline()?);
+            if self.eat(')') {
+                return Ok(args);
+            }
+            if !self.eat(',') {
+                return Err("expected `,` or `)` in arguments".to_string());
+            }
+        }
+    }
+}
+
+fn call_expr(name: String, args: Vec<Expr>) -> Result<Expr, String> {
+    let Some((_, min, max)) = FUNCTIONS.iter().find(|(f, _, _)| *f == name) else {
+        return Err(format!("unknown function `{}`", name));
+    };
+    if args.len() < *min || args.len() > *max {
+        return Err(format!("`{}` expects {} argument(s), got {}", name, min, args.len()));
+    }
+    Ok(Expr::Call { name, args })
+}
+
+fn is_blank(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::String(s) => s.is_empty(),
+        _ => false,
+    }
+}
+
+fn call(name: &str, args: &[Value]) -> Result<Value, String> {
+    let text = || plain_string(&args[0]);
+    Ok(match name {
+        "uuid" => {
+            let mut bytes: [u8; 16] = rand::random();
+            bytes[6] = (bytes[6] & 0x0f) | 0x40;
+            bytes[8] = (bytes[8] & 0x3f) | 0x80;
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            Value::String(format!(
+                "{}-{}-{}-{}-{}",
+                &hex[..8],
+                &hex[8..12],
+                &hex[12..16],
+                &hex[16..20],
+                &hex[20..]
+            ))
+        }
+        "now" => Value::String(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)),
+        "format" => {
+            let time = match &args[0] {
+                Value::Number(n) => n
+                    .as_i64()
+                    .and_then(|secs| DateTime::from_timestamp(secs, 0))
+                    .ok_or_else(|| format!("invalid timestamp {}", n))?,
+                other => DateTime::parse_from_rfc3339(&plain_string(other))
+                    .map_err(|e| format!("invalid date `{}`: {}", plain_string(other), e))?
+                    .with_timezone(&Utc),
+            };
+            let pattern = plain_string(&args[1]);
+            let items = chrono::format::StrftimeItems::new(&pattern)
+                .parse()
+                .map_err(|_| format!("invalid date format `{}`", pattern))?;
+            Value::String(time.format_with_items(items.iter()).to_string())
+        }
+        "random_int" => {
+            let (min, max) = (integer(&args[0])?, integer(&args[1])?);
+            if min > max {
+                return Err(format!("random_int: {} is greater than {}", min, max));
+            }
+            Value::from(rand::random_range(min..=max))
+        }
+        "base64_encode" => Value::String(BASE64.encode(text())),
+        "sha256" => Value::String(
+            Sha256::digest(text())
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        ),
+        "url_encode" => Value::String(
+            text()
+                .bytes()
+                .map(|b| match b {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                        (b as char).to_string()
+                    }
+                    _ => format!("%{:02X}", b),
+                })
+                .collect(),
+        ),
+        "upper" => Value::String(text().to_uppercase()),
+        "lower" => Value::String(text().to_lowercase()),
+        "length" => Value::from(match &args[0] {
+            Value::String(s) => s.chars().count(),
+            Value::Array(a) => a.len(),
+            Value::Object(o) => o.len(),
+            other => return Err(format!("length: {} has no length", other)),
+        }),
+        "json_encode" => Value::String(args[0].to_string()),
+        _ => return Err(format!("unknown function `{}`", name)),
+    })
+}
+
+fn integer(value: &Value) -> Result<i64, String> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| format!("{} is not an integer", value))
+}
+
+/// Reads numbers, and strings holding a number such as environment variables.
+fn number(value: &Value) -> Option<Number> {
+    match value {
+        Value::Number(n) => Some(n.clone()),
+        Value::String(s) => serde_json::from_str::<Number>(s.trim()).ok(),
+        _ => None,
+    }
+}
+
+fn arithmetic(op: char, left: &Value, right: &Value) -> Result<Value, String> {
+    let (Some(a), Some(b)) = (number(left), number(right)) else {
+        if let (Value::String(a), '+', Value::String(b)) = (left, op, right) {
+            return Ok(Value::String(format!("{}{}", a, b)));
+        }
+        return Err(format!("cannot compute {} {} {}", left, op, right));
+    };
+    let overflow = || format!("{} {} {} overflows", a, op, b);
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        let exact = match op {
+            '+' => Some(a.checked_add(b).ok_or_else(overflow)?),
+            '-' => Some(a.checked_sub(b).ok_or_else(overflow)?),
+            '*' => Some(a.checked_mul(b).ok_or_else(overflow)?),
+            _ if b == 0 => return Err(format!("{} {} 0 divides by zero", a, op)),
+            '%' => Some(a.checked_rem(b).ok_or_else(overflow)?),
+            _ if a.checked_rem(b).ok_or_else(overflow)? == 0 => {
+                Some(a.checked_div(b).ok_or_else(overflow)?)
+            }
+            _ => None,
+        };
+        if let Some(n) = exact {
+            return Ok(Value::from(n));
+        }
+    }
+    let (a, b) = (a.as_f64().unwrap_or(f64::NAN), b.as_f64().unwrap_or(f64::NAN));
+    let result = match op {
+        '+' => a + b,
+        '-' => a - b,
+        '*' => a * b,
+        '/' => a / b,
+        _ => a % b,
+    };
+    Number::from_f64(result)
+        .map(Value::Number)
+        .ok_or_else(|| format!("{} {} {} is not a number", a, op, b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn eval(source: &str) -> Result<Option<Value>, String> {
+        let lookup = |path: &str| {
+            Ok(match path {
+                "user.json.name" => Some(json!("Ada Lovelace")),
+                "user.json.tags" => Some(json!(["a", "b", "c"])),
+                "env.PAGE" => Some(json!("4")),
+                _ => None,
+            })
+        };
+        parse_expression(source)?.evaluate(&lookup)
+    }
+
+    #[test]
+    fn test_filters_and_functions() {
+        assert_eq!(eval("user.json.name | upper").unwrap(), Some(json!("ADA LOVELACE")));
+        assert_eq!(eval("user.json.tags | length").unwrap(), Some(json!(3)));
+        assert_eq!(eval("url_encode(user.json.name)").unwrap(), Some(json!("Ada%20Lovelace")));
+        assert_eq!(eval("'abc' | base64_encode").unwrap(), Some(json!("YWJj")));
+        assert_eq!(
+            eval("sha256('abc')").unwrap(),
+            Some(json!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"))
+        );
+        assert_eq!(eval("user.json.tags | json_encode").unwrap(), Some(json!(r#"["a","b","c"]"#)));
+        assert_eq!(eval("missing.json.x | default('x')").unwrap(), Some(json!("x")));
+        assert_eq!(eval("missing.json.x | upper").unwrap(), None);
+        assert_eq!(eval("0 | format('%Y-%m-%d')").unwrap(), Some(json!("1970-01-01")));
+        assert_eq!(eval("uuid() | length").unwrap(), Some(json!(36)));
+
+        let n = eval("random_int(1, 3)").unwrap().unwrap().as_i64().unwrap();
+        assert!((1..=3).contains(&n));
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(eval("env.PAGE + 1").unwrap(), Some(json!(5)));
+        assert_eq!(eval("2 + 3 * (4 - 1)").unwrap(), Some(json!(11)));
+        assert_eq!(eval("7 / 2").unwrap(), Some(json!(3.5)));
+        assert_eq!(eval("-env.PAGE % 3").unwrap(), Some(json!(-1)));
+        assert_eq!(eval("'a' + 'b'").unwrap(), Some(json!("ab")));
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("9223372036854775807 + 1").is_err());
+        assert!(eval("(-9223372036854775807 - 1) % -1").is_err());
+        assert!(eval("(-9223372036854775807 - 1) / -1").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse_expression("nope()").unwrap_err(), "unknown function `nope`");
+        assert_eq!(
+            parse_expression("random_int(1)").unwrap_err(),
+            "`random_int` expects 2 argument(s), got 1"
+        );
+        assert!(parse_expression("a +").is_err());
+        assert!(parse_expression("'open").is_err());
+        let expr = parse_expression("a.json.b[0] | default(c.json.d) | upper").unwrap();
+        assert_eq!(expr.references(), vec!["a.json.b[0]", "c.json.d"]);
+        assert_eq!(expr.required_references(), vec!["c.json.d"]);
+    }
+}
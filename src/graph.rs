@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+use crate::expr::parse_expression;
+use crate::resolve::{LOOP_VARIABLES, Segment, parse_template};
+use crate::types::Task;
+use crate::vars::VARS;
+
+/// Dependency graph of a workflow, indexed by task position in `Workflow::tasks`.
+pub struct TaskGraph {
+    pub dependencies: Vec<Vec<usize>>,
+    pub dependents: Vec<Vec<usize>>,
+}
+
+impl TaskGraph {
+    /// Builds the graph from `depends_on` plus two implicit kinds of edges that keep
+    /// file-order semantics where workflows rely on them:
+    /// - a task referencing `{{name...}}` depends on the last earlier task registering `name`
+    /// - a `display` task depends on every task declared before it
+    ///
+    /// Unknown `depends_on` names are skipped; `validate::check_graph` reports them.
+    pub fn build(tasks: &[Task]) -> Self {
+        let mut by_name: HashMap<&str, usize> = HashMap::new();
+        for (index, task) in tasks.iter().enumerate() {
+            by_name.entry(task.name.as_str()).or_insert(index);
+        }
+
+        let mut dependencies = vec![Vec::new(); tasks.len()];
+        for (index, task) in tasks.iter().enumerate() {
+            let deps: &mut Vec<usize> = &mut dependencies[index];
+
+            deps.extend(
+                task.depends_on
+                    .iter()
+                    .filter_map(|dep| by_name.get(dep.as_str()).copied()),
+            );
+
+            if task.kind == "display" {
+                deps.extend(0..index);
+            }
+
+            for name in referenced_names(task) {
+                if let Some(registrant) = tasks[..index]
+                    .iter()
+                    .rposition(|t| t.register.as_deref() == Some(name.as_str()))
+                {
+                    deps.push(registrant);
+                }
+            }
+
+            deps.sort_unstable();
+            deps.dedup();
+        }
+
+        let mut dependents = vec![Vec::new(); tasks.len()];
+        for (index, deps) in dependencies.iter().enumerate() {
+            for &dep in deps {
+                dependents[dep].push(index);
+            }
+        }
+
+        TaskGraph {
+            dependencies,
+            dependents,
+        }
+    }
+
+    /// Marks every task the given task transitively depends on.
+    pub fn ancestors(&self, index: usize) -> Vec<bool> {
+        let mut seen = vec![false; self.dependencies.len()];
+        let mut stack = self.dependencies[index].clone();
+        while let Some(dep) = stack.pop() {
+            if !seen[dep] {
+                seen[dep] = true;
+                stack.extend(&self.dependencies[dep]);
+            }
+        }
+        seen
+    }
+}
+
+/// Returns every templatable string of a task.
+pub fn task_strings(task: &Task) -> Vec<&str> {
+    let mut strings: Vec<&str> = vec![task.url.as_str()];
+    strings.extend(task.when.as_deref());
+    strings.extend(task.headers.values().map(String::as_str));
+    strings.extend(task.query.values().map(String::as_str));
+    strings.extend(task.save_as.as_deref());
+    if let Some(auth) = &task.auth {
+        strings.extend(auth.strings().into_iter().map(String::as_str));
+    }
+    let until = task.poll.iter().flat_map(|poll| &poll.until);
+    for expect in task.expect.iter().chain(until) {
+        if let Some(assertion) = expect.assertion() {
+            for operand in assertion.operands() {
+                collect_yaml_strings(operand, &mut strings);
+            }
+            strings.extend(assertion.matches.as_deref());
+        }
+    }
+    if let Some(body) = &task.body {
+        collect_yaml_strings(body, &mut strings);
+    }
+    if let Some(items) = &task.foreach {
+        collect_yaml_strings(items, &mut strings);
+    }
+    for value in task.vars.values() {
+        collect_yaml_strings(value, &mut strings);
+    }
+    strings
+}
+
+/// Returns the references used by every valid `{{...}}` expression of a string.
+pub fn references(s: &str) -> Vec<String> {
+    let mut found = Vec::new();
+    for segment in parse_template(s) {
+        if let Segment::Expression(source) = segment {
+            if let Ok(expr) = parse_expression(source) {
+                found.extend(expr.references().into_iter().map(String::from));
+            }
+        }
+    }
+    found
+}
+
+/// Returns the registry names referenced through `{{name...}}` anywhere in a task.
+pub fn referenced_names(task: &Task) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for s in task_strings(task) {
+        for reference in references(s) {
+            let name = reference.split(['.', '[']).next().unwrap_or_default();
+            if !name.is_empty()
+                && name != "env"
+                && name != VARS
+                && !LOOP_VARIABLES.contains(&name)
+                && !names.iter().any(|n| n == name)
+            {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names
+}
+
+/// Collects every string inside a YAML value.
+pub fn collect_yaml_strings<'a>(value: &'a serde_yaml::Value, out: &mut Vec<&'a str>) {
+    match value {
+        serde_yaml::Value::String(s) => out.push(s),
+        serde_yaml::Value::Sequence(seq) => {
+            for v in seq {
+                collect_yaml_strings(v, out);
+            }
+        }
+        serde_yaml::Value::Mapping(map) => {
+            for v in map.values() {
+                collect_yaml_strings(v, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_tasks(yaml: &str) -> Vec<Task> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_independent_tasks_have_no_dependencies() {
+        let tasks = parse_tasks(
+            r#"
+            - { name: a, type: http, url: "http://localhost/a" }
+            - { name: b, type: http, url: "http://localhost/b" }
+        "#,
+        );
+        let graph = TaskGraph::build(&tasks);
+
+        assert!(graph.dependencies.iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn test_explicit_and_reference_dependencies() {
+        let tasks = parse_tasks(
+            r#"
+            - { name: login, type: http, url: "http://localhost/login", register: session }
+            - { name: other, type: http, url: "http://localhost/other" }
+            - name: profile
+              type: http
+              url: "http://localhost/profile"
+              depends_on: [other]
+              headers:
+                Authorization: "{{session.json.token}}"
+        "#,
+        );
+        let graph = TaskGraph::build(&tasks);
+
+        assert_eq!(graph.dependencies[2], vec![0, 1]);
+        assert_eq!(graph.dependents[0], vec![2]);
+    }
+
+    #[test]
+    fn test_display_waits_for_earlier_tasks() {
+        let tasks = parse_tasks(
+            r#"
+            - { name: a, type: http, url: "http://localhost/a" }
+            - { name: b, type: http, url: "http://localhost/b" }
+            - { name: show, type: display }
+        "#,
+        );
+        let graph = TaskGraph::build(&tasks);
+
+        assert_eq!(graph.dependencies[2], vec![0, 1]);
+        assert_eq!(graph.ancestors(2), vec![true, true, false]);
+    }
+}
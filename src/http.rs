@@ -1,383 +1,869 @@
-use base64;
-use base64::Engine;
-use base64::engine::general_purpose::STANDARD as BASE64;
 use log::{debug, error, info, trace};
-use reqwest;
+use serde::Serialize;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::fmt;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
-use tokio::time::{Duration, sleep};
+use std::time::SystemTime;
+use tokio::time::{Duration, Instant, sleep};
 
-use crate::resolve::resolve_references;
-use crate::types::{Expect, RegisteredResponse, Task};
+use crate::assertion::{Mismatch, check, check_schema};
+use crate::auth::Tokens;
+use crate::client;
+use crate::jsonpath::Path;
+use crate::resolve::resolve_task;
+use crate::types::{
+    Expect, Poll, RegisteredResponse, Retry, RetryOn, Task, default_poll_interval,
+    default_poll_timeout,
+};
 
-pub async fn execute_task(
-    task: &mut Task,
-    client: &reqwest::Client,
-    registry: &HashMap<String, RegisteredResponse>,
-) -> Result<(Value, String), Box<dyn std::error::Error>> {
-    let task_name = task.name.clone();
-    let task_headers = task.headers.clone();
-    let task_body = task.body.clone();
-    let task_body_type = task.body_type.clone();
-    let task_retries = task.retries;
-    let task_retry_delay = task.retry_delay;
-    let task_expect = task.expect.clone();
-    let task_auth = task.auth.clone();
-
-    let mut attempt = 0;
-    loop {
-        attempt += 1;
-        info!("Executing task `{}` (attempt {})...", task_name, attempt);
+/// A response read in full, so it can be checked more than once.
+pub struct HttpResponse {
+    pub status: reqwest::StatusCode,
+    pub headers: reqwest::header::HeaderMap,
+    pub bytes: Vec<u8>,
+    /// Time from sending the request to reading the whole body
+    pub elapsed: Duration,
+}
 
-        let mut headers = reqwest::header::HeaderMap::new();
+impl HttpResponse {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes).into_owned()
+    }
+}
 
-        headers.insert(
-            reqwest::header::HeaderName::from_static("user-agent"),
-            reqwest::header::HeaderValue::from_str(&format!(
-                "crabflow/{}",
-                env!("CARGO_PKG_VERSION")
-            ))
-            .unwrap(),
-        );
+/// Why an attempt failed, matched against `retry_on`.
+struct Failure {
+    /// Status of the response, `None` if no response was received
+    status: Option<reqwest::StatusCode>,
+    /// Whether no response was received in time
+    timeout: bool,
+    /// Whether the response did not meet an expectation
+    expectation: bool,
+    /// Delay requested through `Retry-After` on a 429 or 503 response
+    retry_after: Option<Duration>,
+    /// Expectations the response did not meet
+    unmet: Vec<Unmet>,
+}
 
-        if log::log_enabled!(log::Level::Debug) || log::log_enabled!(log::Level::Trace) {
-            headers.insert(
-                reqwest::header::HeaderName::from_static("x-crabflow-task"),
-                reqwest::header::HeaderValue::from_str(&task_name).unwrap(),
-            );
+impl Failure {
+    fn connection(timeout: bool) -> Self {
+        Failure {
+            status: None,
+            timeout,
+            expectation: false,
+            retry_after: None,
+            unmet: Vec::new(),
         }
+    }
 
-        // Add basic auth if provided
-        if let Some(auth) = &task_auth {
-            let credentials = format!("{}:{}", auth.username, auth.password);
-            let encoded = BASE64.encode(credentials);
-            let auth_header = format!("Basic {}", encoded);
-            headers.insert(
-                reqwest::header::AUTHORIZATION,
-                reqwest::header::HeaderValue::from_str(&auth_header).unwrap(),
-            );
+    fn response(response: &HttpResponse, expectation: bool) -> Self {
+        Failure {
+            status: Some(response.status),
+            timeout: false,
+            expectation,
+            retry_after: retry_after(response),
+            unmet: Vec::new(),
         }
+    }
 
-        for (k, v) in &task_headers {
-            let name = reqwest::header::HeaderName::from_bytes(k.as_bytes()).unwrap();
-            let value = reqwest::header::HeaderValue::from_str(v).unwrap();
-            headers.insert(name, value);
+    fn unmet(response: &HttpResponse, unmet: Vec<Unmet>) -> Self {
+        Failure {
+            unmet,
+            ..Failure::response(response, true)
         }
+    }
 
-        debug!("Request headers: {:?}", headers);
-        let mut req = client
-            .request(task.method.parse()?, &task.url)
-            .headers(headers);
-        trace!("Request URL: {}", task.url);
-        trace!("Request method: {}", task.method);
-
-        let mut task_body = task_body.clone();
-        if let Some(mut body_val) = task_body {
-            resolve_references(&mut body_val, registry);
-            task_body = Some(body_val);
+    fn is_retryable(&self, retry_on: &[RetryOn]) -> bool {
+        let status = self.status.map(|s| s.as_u16());
+        retry_on.is_empty()
+            || retry_on.iter().any(|on| match on {
+                RetryOn::Status(code) => status == Some(*code),
+                RetryOn::StatusClass(class) => status.is_some_and(|s| s / 100 == *class),
+                RetryOn::Connection => status.is_none(),
+                RetryOn::Timeout => self.timeout,
+                RetryOn::Expectation => self.expectation,
+            })
+    }
+}
+
+fn is_timeout(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_timeout())
+}
+
+/// Reads `Retry-After` (seconds or an HTTP date) from 429 and 503 responses.
+fn retry_after(response: &HttpResponse) -> Option<Duration> {
+    if !matches!(response.status.as_u16(), 429 | 503) {
+        return None;
+    }
+    let value = response
+        .headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()
+            .map(|date| date.duration_since(SystemTime::now()).unwrap_or_default()),
+    }
+}
+
+/// Delay before the next attempt: the server's `Retry-After` if it sent one, otherwise
+/// the backoff delay with jitter, both capped by `max_delay`.
+fn next_delay(retry: &Retry, base: Duration, attempt: u32, failure: &Failure) -> Duration {
+    let delay = match failure.retry_after {
+        Some(retry_after) => retry_after,
+        None => {
+            let delay = retry.backoff_delay(base, attempt);
+            let jitter = retry.jitter.clamp(0.0, 1.0);
+            if jitter > 0.0 {
+                delay.mul_f64(1.0 + rand::random_range(-jitter..=jitter))
+            } else {
+                delay
+            }
         }
+    };
+    retry.max_delay.map_or(delay, |max| delay.min(max))
+}
 
-        if let Some(body_val) = task_body {
-            let body_type = task_body_type
-                .clone()
-                .unwrap_or(crate::types::BodyType::Json);
-            trace!("Request body type: {:?}", body_type);
-            match body_type {
-                crate::types::BodyType::Json => {
-                    // Convert YAML to JSON string
-                    let body_json =
-                        serde_json::to_string(&serde_json::to_value(body_val).unwrap())?;
-                    debug!("Request body (JSON): {}", body_json);
-                    req = req
-                        .header("Content-Type", "application/json")
-                        .body(body_json);
-                }
-                crate::types::BodyType::FormUrlencoded => {
-                    let form_data = match body_val {
-                        serde_yaml::Value::Mapping(map) => {
-                            if task.method.to_uppercase() == "GET" {
-                                // For GET requests, append parameters to URL
-                                let query_params: Vec<String> = map
-                                    .iter()
-                                    .map(|(k, v)| {
-                                        let key = k.as_str().unwrap_or_default();
-                                        let value = v.as_str().unwrap_or_default();
-                                        format!("{}={}", key, value)
-                                    })
-                                    .collect();
-                                let query_string = query_params.join("&");
-                                let url = if task.url.contains('?') {
-                                    format!("{}&{}", task.url, query_string)
-                                } else {
-                                    format!("{}?{}", task.url, query_string)
-                                };
-                                req = client.request(task.method.parse()?, &url);
-                                String::new() // No body for GET requests
-                            } else {
-                                // For other methods, send as form body
-                                map.iter()
-                                    .map(|(k, v)| {
-                                        let key = k.as_str().unwrap_or_default();
-                                        let value = v.as_str().unwrap_or_default();
-                                        format!("{}={}", key, value)
-                                    })
-                                    .collect::<Vec<_>>()
-                                    .join("&")
-                            }
-                        }
-                        _ => {
-                            return Err("Form URL encoded body must be a key-value map".into());
-                        }
-                    };
+fn build_request(
+    task: &Task,
+    client: &reqwest::Client,
+    authorization: Option<&str>,
+) -> Result<reqwest::RequestBuilder, Box<dyn std::error::Error + Send + Sync>> {
+    let mut headers = reqwest::header::HeaderMap::new();
+
+    headers.insert(
+        reqwest::header::HeaderName::from_static("user-agent"),
+        reqwest::header::HeaderValue::from_str(&format!(
+            "crabflow/{}",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .unwrap(),
+    );
+
+    if log::log_enabled!(log::Level::Debug) || log::log_enabled!(log::Level::Trace) {
+        headers.insert(
+            reqwest::header::HeaderName::from_static("x-crabflow-task"),
+            reqwest::header::HeaderValue::from_str(&task.name).unwrap(),
+        );
+    }
+
+    if let Some(authorization) = authorization {
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            reqwest::header::HeaderValue::from_str(authorization)?,
+        );
+    }
+
+    // Values may come from responses, so they are checked rather than trusted
+    for (k, v) in &task.headers {
+        let name = reqwest::header::HeaderName::from_bytes(k.as_bytes())
+            .map_err(|e| format!("Task `{}`: invalid header name `{}`: {}", task.name, k, e))?;
+        let value = reqwest::header::HeaderValue::from_str(v)
+            .map_err(|e| format!("Task `{}`: invalid value for header `{}`: {}", task.name, k, e))?;
+        headers.insert(name, value);
+    }
+
+    debug!("Request headers: {:?}", headers);
+    let mut req = client
+        .request(task.method.parse()?, &task.url)
+        .headers(headers);
+    if !task.query.is_empty() {
+        req = req.query(&task.query);
+    }
+    if let Some(timeout) = task.timeout {
+        req = req.timeout(timeout);
+    }
+    trace!("Request URL: {}", task.url);
+    trace!("Request method: {}", task.method);
 
-                    if !form_data.is_empty() {
-                        debug!("Request body (form-urlencoded): {}", form_data);
-                        req = req
-                            .header("Content-Type", "application/x-www-form-urlencoded")
-                            .body(form_data);
+    if let Some(body_val) = task.body.clone() {
+        let body_type = task
+            .body_type
+            .clone()
+            .unwrap_or(crate::types::BodyType::Json);
+        trace!("Request body type: {:?}", body_type);
+        match body_type {
+            crate::types::BodyType::Json => {
+                // Convert YAML to JSON string
+                let body_json =
+                    serde_json::to_string(&serde_json::to_value(body_val).unwrap())?;
+                debug!("Request body (JSON): {}", body_json);
+                req = req
+                    .header("Content-Type", "application/json")
+                    .body(body_json);
+            }
+            crate::types::BodyType::FormUrlencoded => {
+                let form_data = match body_val {
+                    serde_yaml::Value::Mapping(map) => {
+                        if task.method.to_uppercase() == "GET" {
+                            // For GET requests, append parameters to URL, keeping the
+                            // headers, query and timeout already set
+                            let query_params: Vec<(&str, &str)> = map
+                                .iter()
+                                .map(|(k, v)| {
+                                    (k.as_str().unwrap_or_default(), v.as_str().unwrap_or_default())
+                                })
+                                .collect();
+                            req = req.query(&query_params);
+                            String::new() // No body for GET requests
+                        } else {
+                            // For other methods, send as form body
+                            map.iter()
+                                .map(|(k, v)| {
+                                    let key = k.as_str().unwrap_or_default();
+                                    let value = v.as_str().unwrap_or_default();
+                                    format!("{}={}", key, value)
+                                })
+                                .collect::<Vec<_>>()
+                                .join("&")
+                        }
                     }
+                    _ => {
+                        return Err("Form URL encoded body must be a key-value map".into());
+                    }
+                };
+
+                if !form_data.is_empty() {
+                    debug!("Request body (form-urlencoded): {}", form_data);
+                    req = req
+                        .header("Content-Type", "application/x-www-form-urlencoded")
+                        .body(form_data);
                 }
-                crate::types::BodyType::Raw => {
-                    let raw_body = body_val.as_str().unwrap_or_default();
-                    debug!("Request body (raw): {}", raw_body);
-                    req = req.body(raw_body.to_string());
-                }
-                crate::types::BodyType::FormMultipart => {
-                    let mut form = reqwest::multipart::Form::new();
-                    match body_val {
-                        serde_yaml::Value::Mapping(map) => {
-                            for (k, v) in map {
-                                let key = k.as_str().unwrap_or_default().to_string();
-                                let value = v.as_str().unwrap_or_default().to_string();
-                                trace!("Adding form field: {}={}", key, value);
-                                form = form.text(key, value);
-                            }
-                        }
-                        _ => {
-                            return Err("Multipart form body must be a key-value map".into());
+            }
+            crate::types::BodyType::Raw => {
+                let raw_body = body_val.as_str().unwrap_or_default();
+                debug!("Request body (raw): {}", raw_body);
+                req = req.body(raw_body.to_string());
+            }
+            crate::types::BodyType::FormMultipart => {
+                let mut form = reqwest::multipart::Form::new();
+                match body_val {
+                    serde_yaml::Value::Mapping(map) => {
+                        for (k, v) in map {
+                            let key = k.as_str().unwrap_or_default().to_string();
+                            let value = v.as_str().unwrap_or_default().to_string();
+                            trace!("Adding form field: {}={}", key, value);
+                            form = form.text(key, value);
                         }
                     }
-                    debug!("Request body (multipart): {:?}", form);
-                    req = req.multipart(form);
+                    _ => {
+                        return Err("Multipart form body must be a key-value map".into());
+                    }
                 }
+                debug!("Request body (multipart): {:?}", form);
+                req = req.multipart(form);
             }
         }
+    }
 
-        let resp = req.send().await;
-        match resp {
-            Ok(r) => {
-                let status = r.status();
-                let headers = r.headers().clone();
-
-                // Check if we should save as file
-                if let Some(save_path) = &task.save_as {
-                    // Get content type to check if it's a stream
-                    let content_type = headers
-                        .get("content-type")
-                        .and_then(|v| v.to_str().ok())
-                        .unwrap_or("");
-
-                    // If it's a stream, binary content, or an image, save as file
-                    if content_type.contains("stream")
-                        || content_type.contains("octet-stream")
-                        || content_type.starts_with("image/")
-                    {
-                        let mut file = File::create(save_path).await?;
-                        let bytes = r.bytes().await?;
-                        file.write_all(&bytes).await?;
-
-                        // Create a JSON response with file info
-                        let json = serde_json::json!({
-                            "status": status.as_u16(),
-                            "saved_as": save_path,
-                            "content_type": content_type,
-                            "size": bytes.len()
-                        });
-
-                        info!(
-                            "Task `{}` succeeded and saved response to {}",
-                            task_name, save_path
-                        );
-                        return Ok((json, format!("Response saved to {}", save_path)));
-                    }
-                }
+    Ok(req)
+}
 
-                // Handle regular responses as before
-                let text = r.text().await?;
-                debug!("Response status: {}", status);
-                debug!("Response body: {}", text);
-                trace!("Response headers: {:?}", headers);
-
-                // Check all expectations
-                let mut all_expectations_met = true;
-                for expect in &task_expect {
-                    trace!("Checking expectation: {:?}", expect);
-                    match expect {
-                        Expect::Status { code } => {
-                            let status_code = status.as_u16();
-                            debug!("Expected status: {}, got: {}", code, status_code);
-                            if status_code != *code {
-                                error!(
-                                    "Task `{}` failed: expected status {} but got {}",
-                                    task_name, code, status
-                                );
-                                all_expectations_met = false;
-                                break;
-                            }
-                        }
-                        Expect::JsonPath { path, value } => {
-                            let json: Value = serde_json::from_str(&text)?;
-                            let mut current = &json;
-                            for part in path.split('.') {
-                                trace!("Traversing JSON path: {}", part);
-                                if part.contains('[') && part.contains(']') {
-                                    // Handle array indexing
-                                    let (key, index) = part.split_once('[').unwrap();
-                                    let index =
-                                        index.trim_end_matches(']').parse::<usize>().unwrap();
-                                    current = &current[key][index];
-                                } else {
-                                    current = &current[part];
-                                }
-                            }
-                            let current_str = match current {
-                                Value::Null => "null".to_string(),
-                                _ => current.to_string().trim_matches('"').to_string(),
-                            };
-                            debug!(
-                                "JSON path {}: expected {}, got {}",
-                                path, value, current_str
-                            );
-                            if current_str != *value {
-                                error!(
-                                    "Task `{}` failed: expected {} = {} but got {}",
-                                    task_name, path, value, current_str
-                                );
-                                all_expectations_met = false;
-                                break;
-                            }
-                        }
-                        Expect::Raw { contains } => {
-                            debug!("Checking for raw text: {}", contains);
-                            if !text.contains(contains) {
-                                error!(
-                                    "Task `{}` failed: response does not contain '{}'",
-                                    task_name, contains
-                                );
-                                all_expectations_met = false;
-                                break;
-                            }
-                        }
-                    }
-                }
+/// Builds the request of a task with its credentials, which may depend on the rest
+/// of the request.
+async fn prepare_request(
+    task: &Task,
+    client: &reqwest::Client,
+    tokens: &Tokens,
+) -> Result<reqwest::Request, Box<dyn std::error::Error + Send + Sync>> {
+    let authorization = match &task.auth {
+        Some(auth) => tokens.authorization(auth, client).await?,
+        None => None,
+    };
+    let mut request = build_request(task, client, authorization.as_deref())?.build()?;
+    client::pin_server_name(task, &mut request)?;
+    if let Some(auth) = &task.auth {
+        tokens.sign(auth, &mut request).await?;
+    }
+    Ok(request)
+}
 
-                if !all_expectations_met {
-                    if attempt > task_retries {
-                        break;
-                    }
-                    info!(
-                        "Retrying `{}` in {} seconds...",
-                        task_name, task_retry_delay
-                    );
-                    sleep(Duration::from_secs(task_retry_delay)).await;
-                    continue;
+async fn send_request(
+    task: &Task,
+    client: &reqwest::Client,
+    tokens: &Tokens,
+) -> Result<HttpResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let mut started = Instant::now();
+    let request = prepare_request(task, client, tokens).await?;
+    let mut r = client.execute(request).await?;
+    // Digest credentials answer the challenge of the server, sent with a `401`
+    if let (Some(auth), reqwest::StatusCode::UNAUTHORIZED) = (&task.auth, r.status()) {
+        if tokens.challenge(auth, r.url(), r.headers()).await? {
+            let request = prepare_request(task, client, tokens).await?;
+            started = Instant::now();
+            r = client.execute(request).await?;
+        }
+    }
+    let status = r.status();
+    let headers = r.headers().clone();
+    let bytes = r.bytes().await?.to_vec();
+    Ok(HttpResponse {
+        status,
+        headers,
+        bytes,
+        elapsed: started.elapsed(),
+    })
+}
+
+/// An expectation a response did not meet, as logged and recorded in the results.
+#[derive(Debug, Clone, Serialize)]
+pub struct Unmet {
+    /// Expectation type, e.g. `JsonPath`
+    #[serde(rename = "type")]
+    pub expectation: &'static str,
+    /// JSON path, header name or JSON pointer the failure is about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(flatten)]
+    pub mismatch: Mismatch,
+}
+
+impl Unmet {
+    fn new(expectation: &'static str, path: Option<&str>, mismatch: Mismatch) -> Self {
+        Unmet {
+            expectation,
+            path: path.map(str::to_string),
+            mismatch,
+        }
+    }
+
+    /// A failure that is not about a value, such as a body that is not JSON.
+    fn error(
+        expectation: &'static str,
+        path: Option<&str>,
+        operator: &'static str,
+        message: String,
+    ) -> Self {
+        let mismatch = Mismatch {
+            operator,
+            expected: Value::Null,
+            actual: Value::Null,
+            message,
+        };
+        Unmet::new(expectation, path, mismatch)
+    }
+}
+
+/// The expectations a task's response did not meet, returned once no attempt is left.
+#[derive(Debug)]
+pub struct UnmetExpectations(pub Vec<Unmet>);
+
+impl fmt::Display for UnmetExpectations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let messages: Vec<&str> = self.0.iter().map(|u| u.mismatch.message.as_str()).collect();
+        write!(f, "{} expectation(s) not met: {}", self.0.len(), messages.join("; "))
+    }
+}
+
+impl std::error::Error for UnmetExpectations {}
+
+/// Returns every way a response does not meet an expectation.
+fn unmet_expectations(expect: &Expect, response: &HttpResponse, text: &str) -> Vec<Unmet> {
+    trace!("Checking expectation: {:?}", expect);
+    let tag = |expectation: &'static str, path: Option<&str>, mismatches: Vec<Mismatch>| {
+        mismatches
+            .into_iter()
+            .map(|mismatch| Unmet::new(expectation, path, mismatch))
+            .collect()
+    };
+    match expect {
+        Expect::Status { code } => {
+            let status = response.status;
+            debug!("Expected status: {}, got: {}", code, status.as_u16());
+            if code.matches(status.as_u16()) {
+                return Vec::new();
+            }
+            let mismatch = Mismatch {
+                operator: "code",
+                expected: Value::String(code.to_string()),
+                actual: Value::from(status.as_u16()),
+                message: format!("expected status {} but got {}", code, status),
+            };
+            vec![Unmet::new("Status", None, mismatch)]
+        }
+        Expect::JsonPath { path, assertion } => {
+            let json: Value = match serde_json::from_str(text) {
+                Ok(json) => json,
+                Err(e) => {
+                    let message = format!("response is not valid JSON: {}", e);
+                    return vec![Unmet::error("JsonPath", Some(path), "json", message)];
+                }
+            };
+            let current = match Path::parse(path) {
+                Ok(parsed) => parsed.find(&json),
+                Err(e) => return vec![Unmet::error("JsonPath", Some(path), "path", e)],
+            };
+            debug!("JSON path {}: got {:?}", path, current);
+            tag("JsonPath", Some(path), check(assertion, path, current.as_ref()))
+        }
+        Expect::Raw { assertion } => {
+            debug!("Checking raw response: {:?}", assertion);
+            let current = Value::String(text.to_string());
+            tag("Raw", None, check(assertion, "response", Some(&current)))
+        }
+        Expect::Header { name, assertion } => {
+            let values: Vec<String> = response
+                .headers
+                .get_all(name.as_str())
+                .iter()
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                .collect();
+            debug!("Header {}: {:?}", name, values);
+            let current = (!values.is_empty()).then(|| Value::String(values.join(", ")));
+            let subject = format!("header {}", name);
+            tag("Header", Some(name), check(assertion, &subject, current.as_ref()))
+        }
+        Expect::ResponseTime { max_ms } => {
+            let elapsed = response.elapsed.as_millis();
+            debug!("Response time: {}ms", elapsed);
+            if elapsed <= u128::from(*max_ms) {
+                return Vec::new();
+            }
+            let mismatch = Mismatch {
+                operator: "max_ms",
+                expected: Value::from(*max_ms),
+                actual: Value::from(elapsed as u64),
+                message: format!(
+                    "expected a response within {}ms but it took {}ms",
+                    max_ms, elapsed
+                ),
+            };
+            vec![Unmet::new("ResponseTime", None, mismatch)]
+        }
+        Expect::JsonSchema { schema, file } => {
+            let Some(schema) = schema else {
+                let file = file.as_deref().unwrap_or_default();
+                let message = format!("JSON Schema {} was not loaded", file);
+                return vec![Unmet::error("JsonSchema", None, "schema", message)];
+            };
+            match serde_json::from_str(text) {
+                Ok(json) => check_schema(schema, &json)
+                    .into_iter()
+                    .map(|(pointer, mismatch)| Unmet::new("JsonSchema", Some(&pointer), mismatch))
+                    .collect(),
+                Err(e) => {
+                    let message = format!("response is not valid JSON: {}", e);
+                    vec![Unmet::error("JsonSchema", None, "json", message)]
                 }
+            }
+        }
+        Expect::BodySize { assertion } => {
+            let size = Value::from(response.bytes.len());
+            tag("BodySize", None, check(assertion, "body size", Some(&size)))
+        }
+    }
+}
 
-                // If we have a status expectation and it was met, consider it a success
-                let has_status_expectation = task_expect
-                    .iter()
-                    .any(|e| matches!(e, Expect::Status { .. }));
-                if has_status_expectation {
-                    info!(
-                        "Task `{}` succeeded with expected status {}",
-                        task_name, status
+/// Repeats the request until the response meets every `until` expectation. Responses
+/// that do not match yet are expected, so they are only logged at debug level.
+/// Returns `None` if the timeout is reached first.
+async fn poll_until(
+    task: &Task,
+    poll: &Poll,
+    client: &reqwest::Client,
+    tokens: &Tokens,
+) -> Result<Option<HttpResponse>, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let timeout = poll.timeout.unwrap_or_else(default_poll_timeout);
+    let mut interval = poll.interval.unwrap_or_else(default_poll_interval);
+    let mut polls = 0;
+    info!(
+        "Polling `{}` every {:?} for up to {:?}...",
+        task.name, interval, timeout
+    );
+
+    loop {
+        polls += 1;
+        let response = send_request(task, client, tokens).await?;
+        let text = response.text();
+        let pending = poll
+            .until
+            .iter()
+            .find_map(|expect| unmet_expectations(expect, &response, &text).into_iter().next())
+            .map(|unmet| unmet.mismatch.message);
+
+        let Some(reason) = pending else {
+            info!("Task `{}` ready after {} poll(s)", task.name, polls);
+            return Ok(Some(response));
+        };
+        let Some(remaining) = timeout.checked_sub(started.elapsed()).filter(|r| !r.is_zero())
+        else {
+            debug!("Last response of `{}`: {}", task.name, reason);
+            return Ok(None);
+        };
+        let delay = interval.min(remaining);
+        debug!(
+            "Task `{}` not ready yet ({}), polling again in {:?}",
+            task.name, reason, delay
+        );
+        sleep(delay).await;
+        interval = poll.next_interval(interval);
+    }
+}
+
+/// Checks a response against the task's expectations. Returns a `Failure` if the
+/// attempt failed and may be retried.
+async fn handle_response(
+    task: &Task,
+    response: HttpResponse,
+) -> Result<Result<(Value, String), Failure>, Box<dyn std::error::Error + Send + Sync>> {
+    let status = response.status;
+    let headers = &response.headers;
+
+    // Check if we should save as file
+    if let Some(save_path) = &task.save_as {
+        // Get content type to check if it's a stream
+        let content_type = headers
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+
+        // If it's a stream, binary content, or an image, save as file
+        if content_type.contains("stream")
+            || content_type.contains("octet-stream")
+            || content_type.starts_with("image/")
+        {
+            let mut file = File::create(save_path).await?;
+            file.write_all(&response.bytes).await?;
+
+            // Create a JSON response with file info
+            let json = serde_json::json!({
+                "status": status.as_u16(),
+                "saved_as": save_path,
+                "content_type": content_type,
+                "size": response.bytes.len()
+            });
+
+            info!(
+                "Task `{}` succeeded and saved response to {}",
+                task.name, save_path
+            );
+            return Ok(Ok((json, format!("Response saved to {}", save_path))));
+        }
+    }
+
+    // Handle regular responses as before
+    let text = response.text();
+    debug!("Response status: {}", status);
+    debug!("Response body: {}", text);
+    trace!("Response headers: {:?}", headers);
+
+    // Check the expectations, all of them unless the task fails fast
+    let mut unmet = Vec::new();
+    for expect in &task.expect {
+        let failures = unmet_expectations(expect, &response, &text);
+        for failure in &failures {
+            error!("Task `{}` failed: {}", task.name, failure.mismatch.message);
+        }
+        unmet.extend(failures);
+        if task.fail_fast && !unmet.is_empty() {
+            break;
+        }
+    }
+    if !unmet.is_empty() {
+        return Ok(Err(Failure::unmet(&response, unmet)));
+    }
+
+    // If we have a status expectation and it was met, consider it a success;
+    // otherwise the status has to be successful
+    let has_status_expectation = task
+        .expect
+        .iter()
+        .any(|e| matches!(e, Expect::Status { .. }));
+    if !has_status_expectation && !status.is_success() {
+        error!("Task `{}` failed with status {}", task.name, status);
+        debug!("Error response: {}", text);
+        return Ok(Err(Failure::response(&response, false)));
+    }
+
+    // Only try to parse as JSON if we're not using Raw expectation
+    let json: Value = if task.expect.iter().any(|e| matches!(e, Expect::Raw { .. })) {
+        // For Raw expectations, create a simple JSON object with the text
+        serde_json::json!({ "text": text })
+    } else {
+        match serde_json::from_str(&text) {
+            Ok(json) => json,
+            Err(e) => {
+                error!("Failed to parse response as JSON: {}", e);
+                error!("Response text: {}", text);
+                return Ok(Err(Failure::response(&response, true)));
+            }
+        }
+    };
+
+    if has_status_expectation {
+        info!(
+            "Task `{}` succeeded with expected status {}",
+            task.name, status
+        );
+    } else {
+        info!("Task `{}` succeeded", task.name);
+    }
+    Ok(Ok((json, text)))
+}
+
+pub async fn execute_task(
+    task: &mut Task,
+    client: &reqwest::Client,
+    tokens: &Tokens,
+    registry: &HashMap<String, RegisteredResponse>,
+) -> Result<(Value, String), Box<dyn std::error::Error + Send + Sync>> {
+    let retries = task
+        .retry
+        .as_ref()
+        .and_then(|retry| retry.attempts)
+        .unwrap_or(task.retries);
+    let base_delay = task
+        .retry
+        .as_ref()
+        .and_then(|retry| retry.delay)
+        .unwrap_or(Duration::from_secs(task.retry_delay));
+    resolve_task(task, registry).map_err(|e| format!("Task `{}`: {}", task.name, e))?;
+
+    let mut attempt = 0;
+    let failure = loop {
+        attempt += 1;
+        info!("Executing task `{}` (attempt {})...", task.name, attempt);
+
+        let response = match &task.poll {
+            Some(poll) => match poll_until(task, poll, client, tokens).await {
+                Ok(Some(response)) => Ok(response),
+                Ok(None) => {
+                    error!(
+                        "Task `{}` failed: not ready after polling for {:?}",
+                        task.name, poll.timeout.unwrap_or_else(default_poll_timeout)
                     );
-                    // Parse the response JSON instead of creating a new status-only object
-                    let json: Value = if task_expect.iter().any(|e| matches!(e, Expect::Raw { .. }))
-                    {
-                        // For Raw expectations, create a simple JSON object with the text
-                        serde_json::json!({ "text": text })
-                    } else {
-                        match serde_json::from_str(&text) {
-                            Ok(json) => json,
-                            Err(e) => {
-                                error!("Failed to parse response as JSON: {}", e);
-                                error!("Response text: {}", text);
-                                if attempt > task_retries {
-                                    break;
-                                }
-                                info!(
-                                    "Retrying `{}` in {} seconds...",
-                                    task_name, task_retry_delay
-                                );
-                                sleep(Duration::from_secs(task_retry_delay)).await;
-                                continue;
-                            }
-                        }
-                    };
-                    return Ok((json, text));
+                    return Err("Task timed out while polling".into());
                 }
+                Err(e) => Err(e),
+            },
+            None => send_request(task, client, tokens).await,
+        };
 
-                // For other cases, check if status is successful
-                if status.is_success() {
-                    // Only try to parse as JSON if we're not using Raw expectation
-                    let json: Value = if task_expect.iter().any(|e| matches!(e, Expect::Raw { .. }))
-                    {
-                        // For Raw expectations, create a simple JSON object with the text
-                        serde_json::json!({ "text": text })
-                    } else {
-                        match serde_json::from_str(&text) {
-                            Ok(json) => json,
-                            Err(e) => {
-                                error!("Failed to parse response as JSON: {}", e);
-                                error!("Response text: {}", text);
-                                if attempt > task_retries {
-                                    break;
-                                }
-                                info!(
-                                    "Retrying `{}` in {} seconds...",
-                                    task_name, task_retry_delay
-                                );
-                                sleep(Duration::from_secs(task_retry_delay)).await;
-                                continue;
-                            }
-                        }
-                    };
-                    info!("Task `{}` succeeded", task_name);
-                    return Ok((json, text));
+        let failure = match response {
+            Ok(response) => match handle_response(task, response).await? {
+                Ok(result) => return Ok(result),
+                Err(failure) => failure,
+            },
+            Err(e) => {
+                let timeout = is_timeout(e.as_ref());
+                if timeout {
+                    error!("Task `{}` timed out: {}", task.name, e);
                 } else {
-                    error!("Task `{}` failed with status {}", task_name, status);
-                    debug!("Error response: {}", text);
-                    if attempt > task_retries {
-                        break;
-                    }
-                    info!(
-                        "Retrying `{}` in {} seconds...",
-                        task_name, task_retry_delay
-                    );
-                    sleep(Duration::from_secs(task_retry_delay)).await;
+                    error!("Task `{}` error: {}", task.name, e);
                 }
+                Failure::connection(timeout)
             }
-            Err(e) => {
-                error!("Task `{}` error: {}", task_name, e);
-                if attempt > task_retries {
-                    break;
+        };
+
+        // A rejected OAuth2 token is requested again on the next attempt
+        if let (Some(auth), Some(reqwest::StatusCode::UNAUTHORIZED)) = (&task.auth, failure.status) {
+            tokens.invalidate(auth).await;
+        }
+
+        if attempt > retries {
+            error!("Task `{}` exceeded retry limit", task.name);
+            break failure;
+        }
+        let delay = match &task.retry {
+            Some(retry) => {
+                if !failure.is_retryable(&retry.retry_on) {
+                    error!("Task `{}` failed with an error not listed in retry_on", task.name);
+                    break failure;
                 }
-                info!(
-                    "Retrying `{}` in {} seconds...",
-                    task_name, task_retry_delay
+                next_delay(retry, base_delay, attempt, &failure)
+            }
+            None => base_delay,
+        };
+        info!("Retrying `{}` in {:?}...", task.name, delay);
+        sleep(delay).await;
+    };
+
+    if failure.timeout {
+        return Err("Task timed out".into());
+    }
+    if !failure.unmet.is_empty() {
+        return Err(Box::new(UnmetExpectations(failure.unmet)));
+    }
+    Err("Task failed after all retries".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::AsyncReadExt;
+
+    fn task(yaml: &str) -> Task {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// Serves a job that completes after `pending` requests, or never if `None`, returning
+    /// its URL and the time of each request.
+    async fn job(pending: Option<usize>) -> (String, Arc<Mutex<Vec<Instant>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/job", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = socket.read(&mut request).await;
+                let served = {
+                    let mut log = log.lock().unwrap();
+                    log.push(Instant::now());
+                    log.len()
+                };
+                let body = if pending.is_some_and(|pending| served > pending) {
+                    r#"{"state":"done"}"#
+                } else {
+                    r#"{"state":"pending"}"#
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
                 );
-                sleep(Duration::from_secs(task_retry_delay)).await;
+                let _ = socket.write_all(response.as_bytes()).await;
             }
+        });
+        (url, requests)
+    }
+
+    fn poll(yaml: &str) -> Poll {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_poll_durations() {
+        let text = poll("{ until: [], interval: 250ms, timeout: 2m, max_interval: 1.5 }");
+        assert_eq!(text.interval, Some(Duration::from_millis(250)));
+        assert_eq!(text.timeout, Some(Duration::from_secs(120)));
+        assert_eq!(text.max_interval, Some(Duration::from_millis(1500)));
+
+        let seconds = poll("{ until: [], interval: 3 }");
+        assert_eq!(seconds.interval, Some(Duration::from_secs(3)));
+        assert_eq!(seconds.timeout, None);
+        assert!(serde_yaml::from_str::<Poll>("{ until: [], interval: soon }").is_err());
+    }
+
+    #[test]
+    fn test_poll_next_interval() {
+        let capped = poll("{ until: [], interval: 1s, backoff: 2, max_interval: 5s }");
+        let mut interval = capped.interval.unwrap();
+        let mut intervals = Vec::new();
+        for _ in 0..4 {
+            interval = capped.next_interval(interval);
+            intervals.push(interval.as_secs());
         }
+        assert_eq!(intervals, vec![2, 4, 5, 5]);
 
-        if attempt > task_retries {
-            error!("Task `{}` exceeded retry limit", task_name);
-            break;
+        let fixed = poll("{ until: [], interval: 250ms }");
+        assert_eq!(fixed.next_interval(Duration::from_millis(250)), Duration::from_millis(250));
+
+        // Growing past what a `Duration` holds stops at the timeout
+        let huge = poll("{ until: [], interval: 1s, backoff: 1e300, timeout: 1m }");
+        let interval = huge.next_interval(Duration::from_secs(10_000_000_000));
+        assert_eq!(interval, Duration::from_secs(60));
+
+        for backoff in ["0", "0.5", "-2", ".nan", ".inf"] {
+            let yaml = format!("{{ until: [], backoff: {} }}", backoff);
+            assert!(serde_yaml::from_str::<Poll>(&yaml).is_err(), "{}", backoff);
         }
     }
 
-    Err("Task failed after all retries".into())
+    #[tokio::test]
+    async fn test_poll_until_stops_once_ready() {
+        let (url, requests) = job(Some(2)).await;
+        let task = task(&format!(
+            "{{ name: t, type: http, method: GET, url: '{}', poll: {{ until: {{ type: JsonPath, \
+             path: state, value: done }}, interval: 10ms, timeout: 5s }} }}",
+            url
+        ));
+        let poll = task.poll.clone().unwrap();
+
+        let response = poll_until(&task, &poll, &reqwest::Client::new(), &Tokens::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.text(), r#"{"state":"done"}"#);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_poll_until_backs_off_up_to_max_interval() {
+        let (url, requests) = job(None).await;
+        let task = task(&format!(
+            "{{ name: t, type: http, method: GET, url: '{}', poll: {{ until: {{ type: JsonPath, \
+             path: state, value: done }}, interval: 40ms, backoff: 2, max_interval: 100ms, \
+             timeout: 600ms }} }}",
+            url
+        ));
+        let poll = task.poll.clone().unwrap();
+
+        let response = poll_until(&task, &poll, &reqwest::Client::new(), &Tokens::default())
+            .await
+            .unwrap();
+        assert!(response.is_none());
+
+        let times = requests.lock().unwrap().clone();
+        let gaps: Vec<Duration> = times.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert!(gaps.len() >= 4, "{:?}", gaps);
+        // 40ms, then 80ms, then capped at 100ms instead of 160ms
+        assert!(gaps[1] >= Duration::from_millis(80), "{:?}", gaps);
+        assert!(gaps[2] >= Duration::from_millis(100), "{:?}", gaps);
+        assert!(gaps[2] < Duration::from_millis(160), "{:?}", gaps);
+    }
+
+    #[tokio::test]
+    async fn test_poll_timeout_fails_task() {
+        let (url, requests) = job(None).await;
+        let mut task = task(&format!(
+            "{{ name: t, type: http, method: GET, url: '{}', poll: {{ until: {{ type: JsonPath, \
+             path: state, value: done }}, interval: 20ms, timeout: 100ms }} }}",
+            url
+        ));
+
+        let client = reqwest::Client::new();
+        let error = execute_task(&mut task, &client, &Tokens::default(), &HashMap::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Task timed out while polling");
+        assert!(requests.lock().unwrap().len() > 1);
+    }
+
+    #[test]
+    fn test_invalid_headers_are_errors() {
+        let client = reqwest::Client::new();
+        let task = task(
+            "{ name: t, type: http, method: GET, url: 'http://localhost/', \
+             headers: { X-Tok: \"a\\nb\" } }",
+        );
+
+        let error = build_request(&task, &client, None).unwrap_err().to_string();
+        assert!(error.starts_with("Task `t`: invalid value for header `X-Tok`"), "{}", error);
+    }
+
+    #[test]
+    fn test_get_form_keeps_request_settings() {
+        let client = reqwest::Client::new();
+        let task = task(
+            "{ name: t, type: http, method: GET, url: 'http://localhost/search', \
+             query: { page: '2' }, timeout: 500ms, body_type: form-urlencoded, \
+             body: { q: 'a b' } }",
+        );
+
+        let request = build_request(&task, &client, Some("Bearer abc")).unwrap().build().unwrap();
+        assert_eq!(request.url().as_str(), "http://localhost/search?page=2&q=a+b");
+        assert_eq!(request.timeout(), Some(&Duration::from_millis(500)));
+        assert_eq!(request.headers()["authorization"], "Bearer abc");
+        assert!(request.body().is_none());
+    }
 }
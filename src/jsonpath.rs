@@ -0,0 +1,191 @@
+use serde_json::Value;
+use serde_json_path::JsonPath;
+
+/// A path into a JSON document, shared by `JsonPath` expectations and `{{...}}`
+/// references. It is either a JSONPath query starting with `$` (`$..items[?@.active]`)
+/// or the dotted shorthand (`data.users[0].name`), where keys may contain any
+/// character but `.` and `[`, and brackets take JSONPath selectors (`[*]`, `['a.b']`).
+#[derive(Debug)]
+pub struct Path {
+    query: JsonPath,
+    singular: bool,
+}
+
+impl Path {
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let query = if path.starts_with('$') {
+            path.to_string()
+        } else {
+            shorthand_to_query(path)?
+        };
+        let singular = is_singular(&query);
+        let query = JsonPath::parse(&query)
+            .map_err(|e| format!("invalid path `{}`: {}", path, e))?;
+        Ok(Path { query, singular })
+    }
+
+    /// Returns the selected value. A path naming a single node gives that node, or null
+    /// if it is missing; wildcards, slices, filters and descendants give an array of
+    /// every match.
+    pub fn select(&self, value: &Value) -> Value {
+        match self.find(value) {
+            Some(found) => found,
+            None if self.singular => Value::Null,
+            None => Value::Array(Vec::new()),
+        }
+    }
+
+    /// Like `select`, but returns `None` when nothing matches.
+    pub fn find(&self, value: &Value) -> Option<Value> {
+        let nodes = self.query.query(value);
+        if nodes.is_empty() {
+            None
+        } else if self.singular {
+            nodes.first().cloned()
+        } else {
+            Some(Value::Array(nodes.into_iter().cloned().collect()))
+        }
+    }
+}
+
+/// Turns `data.users[0]['a.b']..id` into `$['data']['users'][0]['a.b']..['id']`.
+fn shorthand_to_query(path: &str) -> Result<String, String> {
+    let chars: Vec<char> = path.chars().collect();
+    let mut query = String::from("$");
+    let mut i = 0;
+    let mut expect_name = !path.is_empty() && !path.starts_with('[');
+    while i < chars.len() || expect_name {
+        if expect_name {
+            let start = i;
+            while i < chars.len() && chars[i] != '.' && chars[i] != '[' {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            match name.as_str() {
+                "" => return Err(format!("empty key in `{}`", path)),
+                "*" => query.push_str("[*]"),
+                _ => {
+                    let escaped = name.replace('\\', "\\\\").replace('\'', "\\'");
+                    query.push_str(&format!("['{}']", escaped));
+                }
+            }
+            expect_name = false;
+            continue;
+        }
+        match chars[i] {
+            '.' if chars.get(i + 1) == Some(&'.') => {
+                query.push_str("..");
+                i += 2;
+                expect_name = chars.get(i) != Some(&'[');
+            }
+            '.' => {
+                i += 1;
+                expect_name = true;
+            }
+            '[' => {
+                let end = closing_bracket(&chars, i)
+                    .ok_or_else(|| format!("unterminated `[` in `{}`", path))?;
+                query.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            c => return Err(format!("unexpected `{}` in `{}`", c, path)),
+        }
+    }
+    Ok(query)
+}
+
+/// Finds the `]` closing the bracket opened at `open`, skipping quoted strings and
+/// nested brackets.
+fn closing_bracket(chars: &[char], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut i = open;
+    while i < chars.len() {
+        let c = chars[i];
+        match quote {
+            Some(_) if c == '\\' => i += 1,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '\'' | '"' => quote = Some(c),
+                '[' => depth += 1,
+                ']' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(i);
+                    }
+                }
+                _ => {}
+            },
+        }
+        i += 1;
+    }
+    None
+}
+
+/// A query selects at most one node unless it uses a wildcard, a descendant segment,
+/// a filter, a slice or a union.
+fn is_singular(query: &str) -> bool {
+    let mut quote: Option<char> = None;
+    let mut previous = ' ';
+    for c in query.chars() {
+        match quote {
+            Some(q) if c == q && previous != '\\' => quote = None,
+            Some(_) => {}
+            None => match c {
+                '\'' | '"' => quote = Some(c),
+                '*' | '?' | ':' | ',' => return false,
+                '.' if previous == '.' => return false,
+                _ => {}
+            },
+        }
+        previous = c;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn select(path: &str, value: &Value) -> Value {
+        Path::parse(path).unwrap().select(value)
+    }
+
+    #[test]
+    fn test_shorthand_and_jsonpath() {
+        let doc = json!({
+            "data": {
+                "users": [
+                    {"name": "John", "active": true, "tags": [["a", "b"]]},
+                    {"name": "Jane", "active": false, "tags": []}
+                ],
+                "a.b": 1,
+                "content-type": "json"
+            }
+        });
+
+        assert_eq!(select("data.users[0].name", &doc), json!("John"));
+        assert_eq!(select("data.users[0].tags[0][1]", &doc), json!("b"));
+        assert_eq!(select("data['a.b']", &doc), json!(1));
+        assert_eq!(select("data.content-type", &doc), json!("json"));
+        assert_eq!(select("data.users[-1].name", &doc), json!("Jane"));
+        assert_eq!(select("data.missing", &doc), Value::Null);
+        assert_eq!(select("data.users[*].name", &doc), json!(["John", "Jane"]));
+        assert_eq!(select("data.users.*.name", &doc), json!(["John", "Jane"]));
+        assert_eq!(select("$..users[?@.active == true].name", &doc), json!(["John"]));
+        assert_eq!(select("data..name", &doc), json!(["John", "Jane"]));
+        assert_eq!(select("data..missing", &doc), json!([]));
+        assert_eq!(select("", &doc), doc);
+        assert_eq!(select("[1]", &json!(["x", "y"])), json!("y"));
+    }
+
+    #[test]
+    fn test_invalid_paths() {
+        assert!(Path::parse("items[x]").is_err());
+        assert!(Path::parse("items[0").is_err());
+        assert!(Path::parse("a..").is_err());
+        assert!(Path::parse("$.a[?@.b ==]").is_err());
+    }
+}
@@ -1,22 +1,66 @@
 use std::path::Path;
 
-use clap::{ CommandFactory, Parser };
+use clap::{ CommandFactory, Parser, Subcommand };
 use env_logger::Builder;
 use log::LevelFilter;
 
 mod types;
 mod env;
+mod expr;
+mod jsonpath;
+mod assertion;
+mod auth;
 mod resolve;
+mod graph;
+mod validate;
+mod vars;
 mod http;
+mod client;
 mod workflow;
 
 /// A tool for running REST workflows
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// The workflow file to execute
     #[arg(value_name = "FILENAME")]
     workflow_file: Option<String>,
+
+    /// Maximum number of tasks to run concurrently (overrides `max_concurrency`)
+    #[arg(short, long, value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Set a variable, overriding workflow and task `vars` (repeatable)
+    #[arg(long = "var", value_name = "KEY=VALUE", value_parser = vars::parse_assignment)]
+    vars: Vec<(String, serde_json::Value)>,
+
+    /// Read variables from a YAML file; `--var` takes precedence
+    #[arg(long, value_name = "FILE")]
+    vars_file: Option<String>,
+
+    /// Load environment variables from a `.env` file (repeatable)
+    #[arg(long = "env-file", value_name = "FILE")]
+    env_files: Vec<String>,
+
+    /// Abort before sending any request if an `env` or `vars` reference is undefined
+    #[arg(long)]
+    strict: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check a workflow file for problems without sending any requests
+    #[command(alias = "lint")]
+    Validate {
+        /// The workflow file to check
+        #[arg(value_name = "FILENAME")]
+        workflow_file: Option<String>,
+    },
+    /// Print the JSON Schema of the workflow file format
+    Schema,
 }
 
 #[tokio::main]
@@ -30,12 +74,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args = Args::parse();
 
+    match args.command {
+        Some(Command::Validate { workflow_file }) => {
+            let workflow_file = workflow_file.unwrap_or_else(|| "workflow.yaml".to_string());
+            return validate::report(&workflow_file);
+        }
+        Some(Command::Schema) => {
+            print!("{}", types::workflow_schema());
+            return Ok(());
+        }
+        None => {}
+    }
+
     let workflow_file = args.workflow_file.unwrap_or_else(|| "workflow.yaml".to_string());
     if !Path::new(&workflow_file).exists() {
         println!("{}", Args::command().render_help());
         return Err(format!("Workflow file '{}' (default) not found", workflow_file).into());
     }
-    workflow::execute_workflow(&workflow_file).await?;
+    let mut vars = match &args.vars_file {
+        Some(path) => vars::load_file(path)?,
+        None => Default::default(),
+    };
+    vars.extend(args.vars);
+    let options = workflow::RunOptions {
+        jobs: args.jobs,
+        vars,
+        env_files: args.env_files,
+        strict: args.strict,
+    };
+    workflow::execute_workflow(&workflow_file, options).await?;
 
     Ok(())
 }
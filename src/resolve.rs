@@ -1,73 +1,343 @@
 use log::debug;
+use serde_json::Value;
 use std::collections::HashMap;
-use crate::types::RegisteredResponse;
+use crate::env;
+use crate::expr::parse_expression;
+use crate::jsonpath::Path;
+use crate::types::{RegisteredResponse, Task};
+use crate::vars::VARS;
+
+/// Registry names holding the current element and position while a `foreach` task loops.
+pub const LOOP_VARIABLES: [&str; 2] = ["item", "index"];
+
+/// Splits a reference into its root name and the path after it: `env.HOME` gives
+/// `("env", "HOME")`, `users.json.data[0]` gives `("users", "data[0]")` and
+/// `item[1].id` gives `("item", "[1].id")`.
+pub fn split_reference(reference: &str) -> Result<(&str, &str), String> {
+    let (root, rest) = reference.split_at(reference.find(['.', '[']).unwrap_or(reference.len()));
+    if root == "env" || root == VARS || LOOP_VARIABLES.contains(&root) {
+        return Ok((root, rest.strip_prefix('.').unwrap_or(rest)));
+    }
+    match rest.strip_prefix(".json") {
+        Some(path) if !root.is_empty() && (path.is_empty() || path.starts_with(['.', '['])) => {
+            Ok((root, path.strip_prefix('.').unwrap_or(path)))
+        }
+        _ if root.is_empty() || rest.is_empty() => {
+            Err("expected `env.NAME`, `vars.name` or `name.json.path`".to_string())
+        }
+        _ => {
+            let found = rest.trim_start_matches('.').split(['.', '[']).next().unwrap_or_default();
+            Err(format!("expected `{}.json`, found `{}.{}`", root, root, found))
+        }
+    }
+}
+
+/// Looks up `env.NAME`, `vars.name` or `name.json.path` (without braces) in the environment
+/// or registry. Returns `None` for names that are not registered.
+pub fn lookup_reference(
+    ref_str: &str,
+    registry: &HashMap<String, RegisteredResponse>
+) -> Result<Option<Value>, String> {
+    debug!("Resolving reference: {}", ref_str);
+    let (root, path) = split_reference(ref_str)?;
+    // Handle environment variables first
+    if root == "env" {
+        let value = env::var(path).unwrap_or_else(|| {
+            debug!("Environment variable {} not found", path);
+            "".to_string()
+        });
+        debug!("Resolved env var {} to '{}'", path, value);
+        return Ok(Some(Value::String(value)));
+    }
+    // Variables that are not defined anywhere fall back to the environment
+    if root == VARS {
+        let name = path.split(['.', '[']).next().unwrap_or_default();
+        let defined = registry
+            .get(VARS)
+            .is_some_and(|vars| name.is_empty() || vars.json.get(name).is_some());
+        if !defined {
+            debug!("Variable {} not defined, trying the environment", name);
+            return Ok(env::var(name).filter(|_| path == name).map(Value::String));
+        }
+    }
+    // Then handle registered response references
+    let Some(response) = registry.get(root) else {
+        debug!("No registered response found for: {}", root);
+        return Ok(None);
+    };
+    let value = Path::parse(path)?.select(&response.json);
+    debug!("Resolved {} to {:?}", ref_str, value);
+    Ok(Some(value))
+}
 
+/// A piece of a templated string: literal text or the trimmed contents of a `{{...}}`.
+#[derive(Debug, PartialEq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+    Expression(&'a str),
+}
+
+/// Splits a string into literal text and `{{...}}` expressions. An unterminated `{{`
+/// is kept as text.
+pub fn parse_template(s: &str) -> Vec<Segment<'_>> {
+    let mut segments = Vec::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}").map(|e| start + e) else {
+            break;
+        };
+        if start > 0 {
+            segments.push(Segment::Text(&rest[..start]));
+        }
+        segments.push(Segment::Expression(rest[start + 2..end].trim()));
+        rest = &rest[end + 2..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest));
+    }
+    segments
+}
+
+/// Evaluates the contents of a `{{...}}`. Returns `None` when it uses a reference
+/// that cannot be resolved.
+pub fn evaluate_expression(
+    source: &str,
+    registry: &HashMap<String, RegisteredResponse>
+) -> Result<Option<Value>, String> {
+    let lookup = |reference: &str| lookup_reference(reference, registry);
+    parse_expression(source)
+        .and_then(|expr| expr.evaluate(&lookup))
+        .map_err(|e| format!("`{{{{{}}}}}`: {}", source, e))
+}
+
+/// Replaces every `{{...}}` in a string with the plain string form of its value.
+/// Expressions using unresolvable references are kept as written.
+pub fn render(s: &str, registry: &HashMap<String, RegisteredResponse>) -> Result<String, String> {
+    let mut out = String::new();
+    for segment in parse_template(s) {
+        match segment {
+            Segment::Text(text) => out.push_str(text),
+            Segment::Expression(source) => match evaluate_expression(source, registry)? {
+                Some(value) => out.push_str(&plain_string(&value)),
+                None => {
+                    debug!("Leaving unresolved reference {{{{{}}}}}", source);
+                    out.push_str("{{");
+                    out.push_str(source);
+                    out.push_str("}}");
+                }
+            },
+        }
+    }
+    Ok(out)
+}
+
+/// Resolves a templated string to a value. A string made of a single expression takes
+/// the type of its value, anything else is rendered to a string.
+pub fn render_value(
+    s: &str,
+    registry: &HashMap<String, RegisteredResponse>
+) -> Result<Value, String> {
+    if let [Segment::Expression(source)] = parse_template(s).as_slice() {
+        if let Some(value) = evaluate_expression(source, registry)? {
+            return Ok(value);
+        }
+    }
+    render(s, registry).map(Value::String)
+}
+
+/// Resolves every templated string inside a YAML value with `render_value`.
 pub fn resolve_references(
     body: &mut serde_yaml::Value,
     registry: &HashMap<String, RegisteredResponse>
-) {
+) -> Result<(), String> {
     match body {
         serde_yaml::Value::Mapping(map) => {
             for (_, value) in map.iter_mut() {
-                resolve_references(value, registry);
+                resolve_references(value, registry)?;
             }
         }
         serde_yaml::Value::Sequence(seq) => {
             for value in seq.iter_mut() {
-                resolve_references(value, registry);
+                resolve_references(value, registry)?;
             }
         }
-        serde_yaml::Value::String(s) => {
-            if s.starts_with("{{") && s.ends_with("}}") {
-                let ref_str = s.trim_matches(|c| (c == '{' || c == '}'));
-                // Handle environment variables first
-                if let Some(env_var) = ref_str.strip_prefix("env.") {
-                    let value = std::env::var(env_var).unwrap_or_else(|_| {
-                        debug!("Environment variable {} not found", env_var);
-                        "".to_string()
-                    });
-                    debug!("Resolved env var {} to '{}'", env_var, value);
-                    *body = serde_yaml::Value::String(value);
-                    return;
-                }
-                // Then handle registered response references
-                debug!("Resolving reference: {}", ref_str);
-                let parts: Vec<&str> = ref_str.split('.').collect();
-                if parts.len() >= 2 {
-                    debug!("Looking for registered response: {}", parts[0]);
-                    if let Some(response) = registry.get(parts[0]) {
-                        let json = &response.json;
-                        debug!("Found registered response: {:?}", json);
-                        let mut current = json;
-                        // Skip the first part (task name) and second part (json) since the response is already JSON
-                        for part in parts[2..].iter() {
-                            debug!("Traversing path: {}", part);
-                            if part.contains('[') && part.contains(']') {
-                                let (key, index) = part.split_once('[').unwrap();
-                                let index = index.trim_end_matches(']').parse::<usize>().unwrap();
-                                current = &current[key][index];
-                            } else {
-                                current = &current[part];
-                            }
-                            debug!("Current value: {:?}", current);
-                        }
-                        // Convert the JSON value to YAML value and replace the string
-                        let yaml_value = serde_yaml::to_value(current).unwrap();
-                        debug!("Converting to YAML: {:?}", yaml_value);
-                        *body = yaml_value;
-                    } else {
-                        debug!("No registered response found for: {}", parts[0]);
+        serde_yaml::Value::String(s) if s.contains("{{") => {
+            // Convert the JSON value to YAML value and replace the string
+            let yaml_value = serde_yaml::to_value(render_value(s, registry)?).unwrap();
+            debug!("Converting to YAML: {:?}", yaml_value);
+            *body = yaml_value;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Renders every templated field of a task once, before its first request, so
+/// retries and polls send the same values.
+pub fn resolve_task(
+    task: &mut Task,
+    registry: &HashMap<String, RegisteredResponse>
+) -> Result<(), String> {
+    task.url = render(&task.url, registry)?;
+    for value in task.headers.values_mut().chain(task.query.values_mut()) {
+        *value = render(value, registry)?;
+    }
+    if let Some(auth) = &mut task.auth {
+        for value in auth.strings_mut() {
+            *value = render(value, registry)?;
+        }
+    }
+    let until = task.poll.iter_mut().flat_map(|poll| &mut poll.until);
+    for expect in task.expect.iter_mut().chain(until) {
+        let Some(assertion) = expect.assertion_mut() else {
+            continue;
+        };
+        for operand in assertion.operands_mut() {
+            resolve_references(operand, registry)?;
+        }
+        if let Some(pattern) = &mut assertion.matches {
+            *pattern = render(pattern, registry)?;
+        }
+    }
+    if let Some(save_as) = &mut task.save_as {
+        *save_as = render(save_as, registry)?;
+    }
+    if let Some(body) = &mut task.body {
+        resolve_references(body, registry)?;
+    }
+    Ok(())
+}
+
+const OPERATORS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+/// A parsed `when` condition: a single operand, or `left op right`.
+pub struct Condition<'a> {
+    pub left: &'a str,
+    pub comparison: Option<(&'a str, &'a str)>,
+}
+
+/// Splits a `when` condition into its operands and comparison operator, ignoring
+/// anything inside `{{...}}` or quotes. A condition without operator is a single operand.
+pub fn parse_condition(expr: &str) -> Result<Condition<'_>, String> {
+    let bytes = expr.as_bytes();
+    let mut quote: Option<u8> = None;
+    let mut in_reference = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &expr[i..];
+        match quote {
+            Some(q) if bytes[i] == q => quote = None,
+            Some(_) => {}
+            None if in_reference => in_reference = !rest.starts_with("}}"),
+            None if rest.starts_with("{{") => in_reference = true,
+            None if bytes[i] == b'"' || bytes[i] == b'\'' => quote = Some(bytes[i]),
+            None => {
+                if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                    let (left, right) = (expr[..i].trim(), expr[i + op.len()..].trim());
+                    if left.is_empty() || right.is_empty() {
+                        return Err(format!("missing operand around `{}`", op));
                     }
+                    return Ok(Condition {
+                        left,
+                        comparison: Some((op, right)),
+                    });
                 }
             }
         }
-        _ => {}
+        i += 1;
+    }
+    if quote.is_some() {
+        return Err("unterminated quote".to_string());
+    }
+    if expr.trim().is_empty() {
+        return Err("empty condition".to_string());
+    }
+    Ok(Condition {
+        left: expr.trim(),
+        comparison: None,
+    })
+}
+
+fn condition_operand(
+    operand: &str,
+    registry: &HashMap<String, RegisteredResponse>
+) -> Result<Value, String> {
+    if let [Segment::Expression(source)] = parse_template(operand).as_slice() {
+        return Ok(evaluate_expression(source, registry)?.unwrap_or(Value::Null));
+    }
+    if operand.contains("{{") {
+        return Err(format!("`{}` must be a single `{{{{...}}}}` expression or a literal", operand));
     }
+    for q in ['"', '\''] {
+        if let Some(quoted) = operand.strip_prefix(q).and_then(|o| o.strip_suffix(q)) {
+            return Ok(Value::String(quoted.to_string()));
+        }
+    }
+    serde_yaml::from_str(operand).map_err(|e| format!("invalid literal `{}`: {}", operand, e))
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty() && s != "false",
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(o) => !o.is_empty(),
+    }
+}
+
+pub fn plain_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        _ => value.to_string(),
+    }
+}
+
+/// Evaluates a `when` condition such as `{{login.json.mfa_required}} == true`.
+/// Operands of different types are compared by their string form.
+pub fn evaluate_condition(
+    expr: &str,
+    registry: &HashMap<String, RegisteredResponse>
+) -> Result<bool, String> {
+    let condition = parse_condition(expr)?;
+    let left = condition_operand(condition.left, registry)?;
+    let Some((op, right)) = condition.comparison else {
+        return Ok(is_truthy(&left));
+    };
+    let right = condition_operand(right, registry)?;
+
+    let ordering = match (&left, &right) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ if left == right => Some(std::cmp::Ordering::Equal),
+        _ if plain_string(&left) == plain_string(&right) => Some(std::cmp::Ordering::Equal),
+        _ => None,
+    };
+    debug!("Condition `{}`: {} {} {} -> {:?}", expr, left, op, right, ordering);
+
+    use std::cmp::Ordering::*;
+    Ok(match op {
+        "==" => ordering == Some(Equal),
+        "!=" => ordering != Some(Equal),
+        _ => {
+            let Some(ordering) = ordering else {
+                return Err(format!("cannot compare {} {} {}", left, op, right));
+            };
+            match op {
+                "<" => ordering == Less,
+                "<=" => ordering != Greater,
+                ">" => ordering == Greater,
+                _ => ordering != Less,
+            }
+        }
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::{Auth, Expect};
     use serde_json::json;
     use serde_yaml::Value;
 
@@ -109,7 +379,7 @@ mod tests {
         let mut value: Value = serde_yaml::from_str(yaml_str).unwrap();
         let registry = HashMap::new();
 
-        resolve_references(&mut value, &registry);
+        resolve_references(&mut value, &registry).unwrap();
 
         assert_eq!(value["key"], "test_value");
     }
@@ -133,7 +403,7 @@ mod tests {
         let mut value: Value = serde_yaml::from_str(yaml_str).unwrap();
         let registry = HashMap::new();
 
-        resolve_references(&mut value, &registry);
+        resolve_references(&mut value, &registry).unwrap();
 
         assert_eq!(value["url"]["base"], "http://localhost:8080/basic-auth/user/");
         assert_eq!(value["url"]["password"], "secret123");
@@ -152,7 +422,7 @@ mod tests {
         let mut value: Value = serde_yaml::from_str(yaml_str).unwrap();
         let registry = HashMap::new();
 
-        resolve_references(&mut value, &registry);
+        resolve_references(&mut value, &registry).unwrap();
 
         assert_eq!(value["headers"]["X-Api-Key"], "api-key-123");
     }
@@ -169,7 +439,7 @@ mod tests {
         let mut value: Value = serde_yaml::from_str(yaml_str).unwrap();
         let registry = HashMap::new();
 
-        resolve_references(&mut value, &registry);
+        resolve_references(&mut value, &registry).unwrap();
 
         assert_eq!(value["body"]["foo"], "bar-value");
     }
@@ -184,7 +454,7 @@ mod tests {
         let mut value: Value = serde_yaml::from_str(yaml_str).unwrap();
         let registry = create_test_registry();
 
-        resolve_references(&mut value, &registry);
+        resolve_references(&mut value, &registry).unwrap();
 
         assert_eq!(value["user"], "John");
         assert_eq!(value["setting"], true);
@@ -202,7 +472,7 @@ mod tests {
         let mut value: Value = serde_yaml::from_str(yaml_str).unwrap();
         let registry = create_test_registry();
 
-        resolve_references(&mut value, &registry);
+        resolve_references(&mut value, &registry).unwrap();
 
         assert_eq!(value["config"]["user_info"]["name"], "Jane");
         assert_eq!(value["config"]["user_info"]["age"], 25);
@@ -219,7 +489,7 @@ mod tests {
         let mut value: Value = serde_yaml::from_str(yaml_str).unwrap();
         let registry = create_test_registry();
 
-        resolve_references(&mut value, &registry);
+        resolve_references(&mut value, &registry).unwrap();
 
         assert_eq!(value["users"][0], "John");
         assert_eq!(value["users"][1], "Jane");
@@ -233,7 +503,7 @@ mod tests {
         let mut value: Value = serde_yaml::from_str(yaml_str).unwrap();
         let registry = create_test_registry();
 
-        resolve_references(&mut value, &registry);
+        resolve_references(&mut value, &registry).unwrap();
 
         assert_eq!(value["args"]["foo"][0], "bar");
     }
@@ -246,11 +516,59 @@ mod tests {
         let mut value: Value = serde_yaml::from_str(yaml_str).unwrap();
         let registry = create_test_registry();
 
-        resolve_references(&mut value, &registry);
+        resolve_references(&mut value, &registry).unwrap();
 
         assert_eq!(value["foo"], "bar");
     }
 
+    #[test]
+    fn test_evaluate_condition() {
+        unsafe {
+            std::env::set_var("WHEN_STAGE", "prod");
+        }
+        let registry = create_test_registry();
+
+        assert!(evaluate_condition("{{task1.json.data.settings.enabled}} == true", &registry).unwrap());
+        assert!(evaluate_condition("{{task1.json.data.users[0].age}} > 29", &registry).unwrap());
+        assert!(evaluate_condition("{{task1.json.data.users[1].name}} != 'John'", &registry).unwrap());
+        assert!(evaluate_condition("{{env.WHEN_STAGE}} == prod", &registry).unwrap());
+        assert!(evaluate_condition("{{task1.json.data.missing}} == null", &registry).unwrap());
+        assert!(evaluate_condition("{{task1.json.data.users}}", &registry).unwrap());
+        assert!(!evaluate_condition("{{urlencoded.json.args.bar}}", &registry).unwrap());
+        assert!(evaluate_condition("{{task1.json.data.users[0].age}} == '30'", &registry).unwrap());
+        assert!(evaluate_condition("{{task1.json.data}} > 1", &registry).is_err());
+        assert!(parse_condition("== 1").is_err());
+    }
+
+    #[test]
+    fn test_resolve_loop_variables() {
+        let mut registry = create_test_registry();
+        registry.insert("item".to_string(), RegisteredResponse {
+            json: json!({"id": 7, "tags": ["a", "b"]}),
+            text: String::new(),
+        });
+        registry.insert("index".to_string(), RegisteredResponse {
+            json: json!(2),
+            text: String::new(),
+        });
+        let yaml_str = r#"
+            id: "{{item.id}}"
+            tag: "{{item.tags[1]}}"
+            position: "{{index}}"
+        "#;
+        let mut value: Value = serde_yaml::from_str(yaml_str).unwrap();
+
+        resolve_references(&mut value, &registry).unwrap();
+
+        assert_eq!(value["id"], 7);
+        assert_eq!(value["tag"], "b");
+        assert_eq!(value["position"], 2);
+        assert_eq!(
+            render("/users/{{item.id}}?n={{index}}&{{other.json.x}}", &registry).unwrap(),
+            "/users/7?n=2&{{other.json.x}}"
+        );
+    }
+
     #[test]
     fn test_non_reference_string() {
         let yaml_str = r#"
@@ -259,8 +577,62 @@ mod tests {
         let mut value: Value = serde_yaml::from_str(yaml_str).unwrap();
         let registry = HashMap::new();
 
-        resolve_references(&mut value, &registry);
+        resolve_references(&mut value, &registry).unwrap();
 
         assert_eq!(value["key"], "regular string");
     }
+
+    #[test]
+    fn test_render_inline_references() {
+        unsafe {
+            std::env::set_var("RENDER_HOST", "api.local");
+        }
+        let registry = create_test_registry();
+
+        assert_eq!(
+            render("https://{{env.RENDER_HOST}}/users/{{ task1.json.data.users[1].age }}/x", &registry).unwrap(),
+            "https://api.local/users/25/x"
+        );
+        assert_eq!(
+            render("Bearer {{task1.json.data.users[0].name}} {{unknown.json.x}} {{open", &registry).unwrap(),
+            "Bearer John {{unknown.json.x}} {{open"
+        );
+        assert_eq!(render_value("{{task1.json.data.users[0].age}}", &registry).unwrap(), json!(30));
+        assert_eq!(render_value("{{task1.json.data.users[0].age}}s", &registry).unwrap(), json!("30s"));
+        assert_eq!(
+            parse_template("a{{ b }}{{c}}"),
+            vec![Segment::Text("a"), Segment::Expression("b"), Segment::Expression("c")]
+        );
+    }
+
+    #[test]
+    fn test_resolve_task_fields() {
+        let registry = create_test_registry();
+        let mut task: Task = serde_yaml::from_str(
+            r#"
+            name: fetch
+            type: http
+            method: GET
+            url: "http://localhost/users/{{task1.json.data.users[0].name}}"
+            headers: { X-Age: "{{task1.json.data.users[1].age}}" }
+            query: { foo: "{{urlencoded.json.args.foo[0]}}" }
+            auth: { username: "{{task1.json.data.users[1].name}}", password: secret }
+            expect: { type: JsonPath, path: age, equals: "{{task1.json.data.users[1].age}}" }
+            save_as: "{{task1.json.data.users[0].name}}.json"
+        "#,
+        )
+        .unwrap();
+
+        resolve_task(&mut task, &registry).unwrap();
+
+        assert_eq!(task.url, "http://localhost/users/John");
+        assert_eq!(task.headers["X-Age"], "25");
+        assert_eq!(task.query["foo"], "bar");
+        assert!(matches!(task.auth, Some(Auth::Basic(basic)) if basic.username == "Jane"));
+        assert!(matches!(
+            &task.expect[0],
+            Expect::JsonPath { assertion, .. } if assertion.equals == Some(serde_yaml::Value::from(25))
+        ));
+        assert_eq!(task.save_as.as_deref(), Some("John.json"));
+    }
 }
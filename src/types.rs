@@ -1,14 +1,251 @@
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use serde::de::Deserializer;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::time::Duration;
 
-#[derive(Debug, Deserialize, Clone)]
+/// A condition the response must meet for the task to succeed
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
 #[serde(tag = "type")]
+#[schemars(deny_unknown_fields)]
 pub enum Expect {
-    Status { code: u16 },
-    JsonPath { path: String, value: String },
-    Raw { contains: String },
+    /// HTTP status code
+    Status { code: StatusCodes },
+    /// Value found at a JSONPath (e.g. `$.data.users[0].name`) or dotted path
+    /// (e.g. `data.users[0].name`) of the JSON response
+    JsonPath {
+        path: String,
+        #[serde(flatten)]
+        assertion: Assertion,
+    },
+    /// Raw response body
+    Raw {
+        #[serde(flatten)]
+        assertion: Assertion,
+    },
+    /// Response header, with its values joined by `, ` if it is repeated
+    Header {
+        name: String,
+        #[serde(flatten)]
+        assertion: Assertion,
+    },
+    /// Maximum time from sending the request to reading the whole response
+    ResponseTime { max_ms: u64 },
+    /// Size of the response body in bytes
+    BodySize {
+        #[serde(flatten)]
+        assertion: Assertion,
+    },
+    /// JSON response valid against a JSON Schema, given inline or as a file relative
+    /// to the workflow
+    JsonSchema {
+        #[serde(default)]
+        schema: Option<Value>,
+        #[serde(default)]
+        file: Option<String>,
+    },
+}
+
+impl Expect {
+    /// Returns the operators checked by this expectation, if it takes any.
+    pub fn assertion(&self) -> Option<&Assertion> {
+        match self {
+            Expect::JsonPath { assertion, .. }
+            | Expect::Raw { assertion }
+            | Expect::Header { assertion, .. }
+            | Expect::BodySize { assertion } => Some(assertion),
+            Expect::Status { .. } | Expect::ResponseTime { .. } | Expect::JsonSchema { .. } => None,
+        }
+    }
+
+    pub fn assertion_mut(&mut self) -> Option<&mut Assertion> {
+        match self {
+            Expect::JsonPath { assertion, .. }
+            | Expect::Raw { assertion }
+            | Expect::Header { assertion, .. }
+            | Expect::BodySize { assertion } => Some(assertion),
+            Expect::Status { .. } | Expect::ResponseTime { .. } | Expect::JsonSchema { .. } => None,
+        }
+    }
+}
+
+/// Accepted status codes: a code, a class such as `2xx`, or a list of them
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(untagged)]
+pub enum StatusCodes {
+    One(StatusCode),
+    Any(Vec<StatusCode>),
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, JsonSchema)]
+#[serde(untagged)]
+pub enum StatusCode {
+    /// Exact status code
+    Exact(u16),
+    /// Status class, e.g. `2xx`
+    Class(#[schemars(with = "String", pattern(r"^[1-5][xX]{2}$"))] StatusClass),
+}
+
+/// First digit of a status class such as `2xx`
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct StatusClass(pub u16);
+
+impl TryFrom<String> for StatusClass {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_bytes() {
+            [digit @ b'1'..=b'5', b'x' | b'X', b'x' | b'X'] => Ok(StatusClass(u16::from(digit - b'0'))),
+            _ => Err(format!("invalid status class `{}` (expected e.g. `2xx`)", s)),
+        }
+    }
+}
+
+impl StatusCodes {
+    fn codes(&self) -> &[StatusCode] {
+        match self {
+            StatusCodes::One(code) => std::slice::from_ref(code),
+            StatusCodes::Any(codes) => codes,
+        }
+    }
+
+    pub fn matches(&self, status: u16) -> bool {
+        self.codes().iter().any(|code| match code {
+            StatusCode::Exact(code) => *code == status,
+            StatusCode::Class(StatusClass(class)) => status / 100 == *class,
+        })
+    }
+}
+
+impl std::fmt::Display for StatusCodes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let codes: Vec<String> = self
+            .codes()
+            .iter()
+            .map(|code| match code {
+                StatusCode::Exact(code) => code.to_string(),
+                StatusCode::Class(StatusClass(class)) => format!("{}xx", class),
+            })
+            .collect();
+        write!(f, "{}", codes.join(" or "))
+    }
+}
+
+/// Checks on a value; every operator given must hold. Operands can use `{{...}}`
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+#[schemars(transform = alias("in", "one_of"))]
+pub struct Assertion {
+    /// Equal to this text, comparing values that are not strings by their JSON text
+    #[serde(default)]
+    #[schemars(with = "Option<Value>")]
+    pub value: Option<serde_yaml::Value>,
+    /// Equal to this value, of the same type
+    #[serde(default)]
+    #[schemars(with = "Option<Value>")]
+    pub equals: Option<serde_yaml::Value>,
+    /// Different from this value
+    #[serde(default)]
+    #[schemars(with = "Option<Value>")]
+    pub not_equals: Option<serde_yaml::Value>,
+    /// Greater than this number (or string, in lexical order)
+    #[serde(default)]
+    #[schemars(with = "Option<Value>")]
+    pub gt: Option<serde_yaml::Value>,
+    /// Greater than or equal to this number (or string)
+    #[serde(default)]
+    #[schemars(with = "Option<Value>")]
+    pub gte: Option<serde_yaml::Value>,
+    /// Less than this number (or string)
+    #[serde(default)]
+    #[schemars(with = "Option<Value>")]
+    pub lt: Option<serde_yaml::Value>,
+    /// Less than or equal to this number (or string)
+    #[serde(default)]
+    #[schemars(with = "Option<Value>")]
+    pub lte: Option<serde_yaml::Value>,
+    /// Matches this regular expression
+    #[serde(default)]
+    pub matches: Option<String>,
+    /// Whether the path must select something
+    #[serde(default)]
+    pub exists: Option<bool>,
+    /// Whether the path must select nothing
+    #[serde(default)]
+    pub not_exists: Option<bool>,
+    /// JSON type of the value (`type` itself names the expectation)
+    #[serde(default)]
+    pub is: Option<JsonType>,
+    /// Number of characters, elements or keys
+    #[serde(default)]
+    #[schemars(with = "Option<Value>")]
+    pub length: Option<serde_yaml::Value>,
+    /// Substring of a string, element of an array or key of an object
+    #[serde(default)]
+    #[schemars(with = "Option<Value>")]
+    pub contains: Option<serde_yaml::Value>,
+    /// One of the values of this list
+    #[serde(default, rename = "in", alias = "one_of")]
+    #[schemars(with = "Option<Value>")]
+    pub one_of: Option<serde_yaml::Value>,
+}
+
+impl Assertion {
+    /// Returns the operands that may hold `{{...}}` references.
+    pub fn operands(&self) -> impl Iterator<Item = &serde_yaml::Value> {
+        [
+            &self.value,
+            &self.equals,
+            &self.not_equals,
+            &self.gt,
+            &self.gte,
+            &self.lt,
+            &self.lte,
+            &self.length,
+            &self.contains,
+            &self.one_of,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    pub fn operands_mut(&mut self) -> impl Iterator<Item = &mut serde_yaml::Value> {
+        [
+            &mut self.value,
+            &mut self.equals,
+            &mut self.not_equals,
+            &mut self.gt,
+            &mut self.gte,
+            &mut self.lt,
+            &mut self.lte,
+            &mut self.length,
+            &mut self.contains,
+            &mut self.one_of,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operands().next().is_none()
+            && self.matches.is_none()
+            && self.exists.is_none()
+            && self.not_exists.is_none()
+            && self.is.is_none()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Array,
+    Object,
+    Null,
 }
 
 pub fn deserialize_expect<'de, D>(deserializer: D) -> Result<Vec<Expect>, D::Error>
@@ -18,23 +255,104 @@ where
     #[derive(Deserialize)]
     #[serde(untagged)]
     enum SingleOrVec {
-        Single(Expect),
+        Single(Box<Expect>),
         Vec(Vec<Expect>),
     }
 
     match SingleOrVec::deserialize(deserializer)? {
-        SingleOrVec::Single(expect) => Ok(vec![expect]),
+        SingleOrVec::Single(expect) => Ok(vec![*expect]),
         SingleOrVec::Vec(expects) => Ok(expects),
     }
 }
 
-#[derive(Debug, Deserialize)]
+// Schema counterpart of `deserialize_expect`
+/// A single expectation or a list of them
+#[derive(JsonSchema)]
+#[schemars(rename = "Expectations")]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum ExpectSchema {
+    Single(Box<Expect>),
+    Vec(Vec<Expect>),
+}
+
+/// A crabflow workflow file
+#[derive(Debug, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 pub struct Workflow {
+    /// Name of the workflow
     pub name: String,
+    /// Maximum number of tasks running at the same time
+    #[serde(default = "default_max_concurrency")]
+    pub max_concurrency: usize,
+    /// Time after which the whole run is aborted
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub timeout: Option<Duration>,
+    /// Settings applied to every task that does not set them itself
+    #[serde(default)]
+    pub defaults: TaskDefaults,
+    /// Named client profiles, selected by tasks with `client`
+    #[serde(default)]
+    pub clients: HashMap<String, TaskDefaults>,
+    /// `.env` file loaded before the run, relative to the workflow file
+    #[serde(default)]
+    pub env_file: Option<String>,
+    /// Abort before sending any request if an `env` or `vars` reference is undefined
+    #[serde(default)]
+    pub strict: bool,
+    /// Variables available to every task as `{{vars.name}}`
+    #[serde(default)]
+    #[schemars(with = "HashMap<String, Value>")]
+    pub vars: HashMap<String, serde_yaml::Value>,
     pub tasks: Vec<Task>,
+    /// Tasks run after all others, even when the run failed or timed out
+    #[serde(default)]
+    pub finally: Vec<Task>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl Workflow {
+    /// Returns the main tasks followed by the `finally` tasks, as they are validated.
+    pub fn all_tasks(&self) -> Vec<Task> {
+        self.tasks.iter().chain(&self.finally).cloned().collect()
+    }
+}
+
+/// Task settings applied to the tasks that do not set them, workflow-wide or as a
+/// client profile
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct TaskDefaults {
+    /// Default total time allowed for each request
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub timeout: Option<Duration>,
+    /// Default time allowed to establish a connection
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub connect_timeout: Option<Duration>,
+    /// Default time allowed between two reads of the response
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub read_timeout: Option<Duration>,
+    /// Default TLS settings, completed by those of each task
+    #[serde(default)]
+    pub tls: Option<Tls>,
+    /// Default proxy
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Default hosts reached without the proxy
+    #[serde(default)]
+    pub no_proxy: Option<Vec<String>>,
+    /// Default maximum number of redirects followed
+    #[serde(default)]
+    pub max_redirects: Option<usize>,
+    /// Headers sent by every task that does not set them itself
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
 pub enum BodyType {
     FormUrlencoded,
@@ -43,45 +361,622 @@ pub enum BodyType {
     FormMultipart,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[schemars(deny_unknown_fields, transform = alias("foreach", "loop"))]
 pub struct Task {
+    /// Unique identifier for the task
     pub name: String,
+    /// Type of task
     #[serde(rename = "type")]
+    #[schemars(with = "TaskKind")]
     pub kind: String,
+    /// HTTP method (GET, POST, PUT, DELETE, etc.)
     #[serde(default)]
     pub method: String,
+    /// Target URL
     #[serde(default)]
     pub url: String,
+    /// Custom HTTP headers
     #[serde(default)]
     pub headers: HashMap<String, String>,
+    /// Query parameters appended to the URL
     #[serde(default)]
+    pub query: HashMap<String, String>,
+    /// Request body
+    #[serde(default)]
+    #[schemars(with = "Option<Value>")]
     pub body: Option<serde_yaml::Value>,
+    /// Encoding of the request body (default: json)
     #[serde(default)]
     pub body_type: Option<BodyType>,
+    /// Runs the task once per element of a list or a `{{...}}` reference to one,
+    /// exposing `{{item}}` and `{{index}}`
+    #[serde(default, alias = "loop")]
+    #[schemars(with = "Option<Value>")]
+    pub foreach: Option<serde_yaml::Value>,
+    /// Number of loop iterations running at the same time
+    #[serde(default = "default_foreach_concurrency")]
+    pub foreach_concurrency: usize,
+    /// Names of tasks that must complete before this task
     #[serde(default)]
     pub depends_on: Vec<String>,
+    /// Condition deciding whether the task runs, e.g. `{{login.json.mfa_required}} == true`
+    #[serde(default)]
+    pub when: Option<String>,
+    /// Variables for this task only, overriding the workflow `vars`
+    #[serde(default)]
+    #[schemars(with = "HashMap<String, Value>")]
+    pub vars: HashMap<String, serde_yaml::Value>,
+    /// Keep running dependent tasks when this task fails
+    #[serde(default)]
+    pub continue_on_error: bool,
+    /// Run this task even after another task failed or the workflow timed out
+    #[serde(default)]
+    pub always: bool,
+    /// Number of retry attempts
     #[serde(default = "default_retries")]
     pub retries: u32,
+    /// Delay between retries in seconds
     #[serde(default = "default_retry_delay")]
     pub retry_delay: u64,
+    /// Total time allowed for each request
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub timeout: Option<Duration>,
+    /// Time allowed to establish a connection
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub connect_timeout: Option<Duration>,
+    /// Time allowed between two reads of the response
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub read_timeout: Option<Duration>,
+    /// TLS settings: certificate authorities, client certificate and protocol options
+    #[serde(default)]
+    pub tls: Option<Tls>,
+    /// Proxy URL, e.g. `http://proxy:3128` or `socks5://proxy:1080`
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Hosts reached without the proxy: domains with their subdomains, IP addresses
+    /// and CIDR blocks
+    #[serde(default)]
+    pub no_proxy: Option<Vec<String>>,
+    /// Maximum number of redirects followed, 0 to follow none (default: 10)
+    #[serde(default)]
+    pub max_redirects: Option<usize>,
+    /// Client profile of the workflow's `clients` whose settings the task uses
+    #[serde(default)]
+    pub client: Option<String>,
+    /// Retry policy, overriding `retries` and `retry_delay`
+    #[serde(default)]
+    pub retry: Option<Retry>,
+    /// Repeat the request until the response meets `until`, for long-running operations
+    #[serde(default)]
+    pub poll: Option<Poll>,
+    /// Expectations for the response
     #[serde(default, deserialize_with = "deserialize_expect")]
+    #[schemars(with = "ExpectSchema")]
     pub expect: Vec<Expect>,
+    /// Stop at the first unmet expectation instead of reporting all of them
     #[serde(default)]
-    pub register: Option<String>,
+    pub fail_fast: bool,
+    /// Name to register the response under for reference in other tasks
     #[serde(default)]
-    pub auth: Option<BasicAuth>,
+    pub register: Option<String>,
+    /// Credentials sent with the request
+    #[serde(default, deserialize_with = "deserialize_auth")]
+    #[schemars(with = "Option<AuthSchema>")]
+    pub auth: Option<Auth>,
+    /// File to save binary responses to
     #[serde(default)]
     pub save_as: Option<String>,
+    /// Registered responses shown by a display task (default: all)
     #[serde(default)]
     pub variables: Option<Vec<String>>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+// Schema counterpart of `Task::kind`
+/// Type of task
+#[derive(JsonSchema)]
+#[serde(rename_all = "lowercase")]
+#[allow(dead_code)]
+enum TaskKind {
+    /// Send an HTTP request
+    Http,
+    /// Print registered responses
+    Display,
+}
+
+/// How failed attempts are retried
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct Retry {
+    /// Number of retry attempts (default: `retries`)
+    #[serde(default)]
+    pub attempts: Option<u32>,
+    /// Delay before the first retry (default: `retry_delay`)
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub delay: Option<Duration>,
+    /// How the delay grows between retries
+    #[serde(default)]
+    pub backoff: Backoff,
+    /// Upper bound for the delay, including `Retry-After` values
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub max_delay: Option<Duration>,
+    /// Fraction of the delay randomly added or removed, between 0 and 1
+    #[serde(default)]
+    pub jitter: f64,
+    /// Failures worth retrying (default: all): status codes such as `503`, classes such
+    /// as `5xx`, `connection`, `timeout` or `expectation`
+    #[serde(default)]
+    #[schemars(with = "Vec<RetryOnSchema>")]
+    pub retry_on: Vec<RetryOn>,
+}
+
+impl Retry {
+    /// Delay before the given retry (starting at 1), before jitter and `Retry-After`.
+    pub fn backoff_delay(&self, base: Duration, retry: u32) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Fixed => base,
+            Backoff::Linear => base.saturating_mul(retry),
+            Backoff::Exponential => base.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1))),
+        };
+        self.max_delay.map_or(delay, |max| delay.min(max))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Backoff {
+    #[default]
+    Fixed,
+    Linear,
+    Exponential,
+}
+
+/// A class of failure listed in `retry_on`
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "RetryOnSchema")]
+pub enum RetryOn {
+    Status(u16),
+    /// First digit of the status code, from `4xx` or `5xx`
+    StatusClass(u16),
+    /// No response was received, including timeouts
+    Connection,
+    Timeout,
+    Expectation,
+}
+
+// Schema counterpart of `RetryOn`
+/// A status code, a status class such as `5xx`, `connection`, `timeout` or `expectation`
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "RetryOn")]
+#[serde(untagged)]
+enum RetryOnSchema {
+    Status(u16),
+    Name(#[schemars(pattern(r"^([1-5]xx|connection|timeout|expectation)$"))] String),
+}
+
+impl TryFrom<RetryOnSchema> for RetryOn {
+    type Error = String;
+
+    fn try_from(value: RetryOnSchema) -> Result<Self, Self::Error> {
+        match value {
+            RetryOnSchema::Status(code) => Ok(RetryOn::Status(code)),
+            RetryOnSchema::Name(name) => match name.as_str() {
+                "connection" => Ok(RetryOn::Connection),
+                "timeout" => Ok(RetryOn::Timeout),
+                "expectation" => Ok(RetryOn::Expectation),
+                _ => name
+                    .strip_suffix("xx")
+                    .and_then(|digit| digit.parse::<u16>().ok())
+                    .filter(|digit| (1..=5).contains(digit))
+                    .map(RetryOn::StatusClass)
+                    .ok_or_else(|| format!("unknown retry_on value `{}`", name)),
+            },
+        }
+    }
+}
+
+/// Parses `500ms`, `1.5s`, `2m` or `1h`; a bare number is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration `{}`", s))?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("invalid duration unit in `{}`", s)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("invalid duration `{}`: {}", s, e))
+}
+
+// Schema counterpart of `deserialize_optional_duration`
+/// Seconds, or a string with a unit such as `500ms`, `2s` or `1m`
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "Duration")]
+#[serde(untagged)]
+enum DurationSchema {
+    Seconds(f64),
+    Text(#[schemars(pattern(r"^\s*[0-9.]+\s*(ms|s|m|h)?\s*$"))] String),
+}
+
+pub fn deserialize_optional_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let duration = match DurationSchema::deserialize(deserializer)? {
+        DurationSchema::Seconds(seconds) => Duration::try_from_secs_f64(seconds)
+            .map_err(|e| format!("invalid duration `{}`: {}", seconds, e)),
+        DurationSchema::Text(text) => parse_duration(&text),
+    };
+    duration.map(Some).map_err(serde::de::Error::custom)
+}
+
+/// TLS settings of a task's connections. Files are relative to the workflow.
+#[derive(Debug, Default, Deserialize, Clone, PartialEq, Eq, Hash, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct Tls {
+    /// PEM file of certificate authorities trusted besides those of the system
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    /// Client certificate for mutual TLS: a PEM file used with `client_key`, or a
+    /// PKCS#12 file (`.p12`, `.pfx`) holding the key as well
+    #[serde(default)]
+    pub client_cert: Option<String>,
+    /// PEM file of the private key of `client_cert`, in PKCS#8 format
+    #[serde(default)]
+    pub client_key: Option<String>,
+    /// Password of a PKCS#12 `client_cert`
+    #[serde(default)]
+    pub client_cert_password: Option<String>,
+    /// Accept any server certificate, for test environments only
+    #[serde(default)]
+    pub insecure_skip_verify: Option<bool>,
+    /// Lowest TLS version accepted
+    #[serde(default)]
+    #[schemars(with = "Option<TlsVersionSchema>")]
+    pub min_version: Option<TlsVersion>,
+    /// Host name replacing the host of the URL, which still gives the address to connect
+    /// to: it is sent in SNI, expected in the server certificate, and used in the `Host`
+    /// header and in `aws_sigv4` and `hmac` signatures
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+impl Tls {
+    /// Completes these settings with defaults, field by field.
+    pub fn or(self, defaults: &Tls) -> Tls {
+        Tls {
+            ca_cert: self.ca_cert.or_else(|| defaults.ca_cert.clone()),
+            client_cert: self.client_cert.or_else(|| defaults.client_cert.clone()),
+            client_key: self.client_key.or_else(|| defaults.client_key.clone()),
+            client_cert_password: self
+                .client_cert_password
+                .or_else(|| defaults.client_cert_password.clone()),
+            insecure_skip_verify: self.insecure_skip_verify.or(defaults.insecure_skip_verify),
+            min_version: self.min_version.or(defaults.min_version),
+            server_name: self.server_name.or_else(|| defaults.server_name.clone()),
+        }
+    }
+
+    /// Returns the fields naming files.
+    pub fn files_mut(&mut self) -> Vec<&mut String> {
+        let mut files = Vec::new();
+        files.extend(&mut self.ca_cert);
+        files.extend(&mut self.client_cert);
+        files.extend(&mut self.client_key);
+        files
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "TlsVersionSchema")]
+pub enum TlsVersion {
+    V1_0,
+    V1_1,
+    V1_2,
+}
+
+impl TryFrom<TlsVersionSchema> for TlsVersion {
+    type Error = String;
+
+    fn try_from(version: TlsVersionSchema) -> Result<Self, Self::Error> {
+        let text = match version {
+            TlsVersionSchema::Number(number) => format!("{:.1}", number),
+            TlsVersionSchema::Text(text) => text,
+        };
+        match text.trim() {
+            "1.0" => Ok(TlsVersion::V1_0),
+            "1.1" => Ok(TlsVersion::V1_1),
+            "1.2" => Ok(TlsVersion::V1_2),
+            "1.3" => Err("TLS 1.3 cannot be required with the system TLS library".to_string()),
+            _ => Err(format!("invalid TLS version `{}`, expected 1.0, 1.1 or 1.2", text)),
+        }
+    }
+}
+
+// Schema counterpart of `TlsVersion`
+/// `1.0`, `1.1` or `1.2`
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "TlsVersion")]
+#[serde(untagged)]
+enum TlsVersionSchema {
+    Number(f64),
+    Text(#[schemars(pattern(r"^1\.[0-2]$"))] String),
+}
+
+/// Polling of a task until its response meets every `until` expectation
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct Poll {
+    /// Expectations the response must meet to stop polling
+    #[serde(deserialize_with = "deserialize_expect")]
+    #[schemars(with = "ExpectSchema")]
+    pub until: Vec<Expect>,
+    /// Delay between polls (default: 5s)
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub interval: Option<Duration>,
+    /// Time after which polling gives up and the task fails (default: 300s)
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub timeout: Option<Duration>,
+    /// Factor the interval is multiplied by after each poll, at least 1
+    #[serde(default, deserialize_with = "deserialize_backoff")]
+    #[schemars(range(min = 1))]
+    pub backoff: Option<f64>,
+    /// Upper bound for the interval when using `backoff`
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    #[schemars(with = "Option<DurationSchema>")]
+    pub max_interval: Option<Duration>,
+}
+
+impl Poll {
+    /// The interval following `interval`, grown by `backoff` up to `max_interval`.
+    pub fn next_interval(&self, interval: Duration) -> Duration {
+        let Some(factor) = self.backoff else {
+            return interval;
+        };
+        let limit = self
+            .max_interval
+            .unwrap_or_else(|| self.timeout.unwrap_or_else(default_poll_timeout));
+        // An interval too long for a `Duration` is as good as the limit
+        Duration::try_from_secs_f64(interval.as_secs_f64() * factor)
+            .map_or(limit, |next| next.min(limit))
+    }
+}
+
+/// Whether a `backoff` factor keeps the interval from shrinking or overflowing.
+pub fn valid_backoff(factor: f64) -> bool {
+    factor.is_finite() && factor >= 1.0
+}
+
+fn deserialize_backoff<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let factor = Option::<f64>::deserialize(deserializer)?;
+    match factor {
+        Some(factor) if !valid_backoff(factor) => Err(serde::de::Error::custom(format!(
+            "invalid `backoff` {}: must be a number of at least 1",
+            factor
+        ))),
+        factor => Ok(factor),
+    }
+}
+
+/// Credentials sent with each request
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Auth {
+    /// HTTP basic authentication
+    Basic(BasicAuth),
+    /// Bearer token, sent as is
+    Bearer { token: String },
+    /// HTTP digest authentication, answering the challenge of the server
+    Digest(BasicAuth),
+    /// OAuth2 client credentials grant
+    #[serde(rename = "oauth2_client_credentials")]
+    OAuth2ClientCredentials(OAuth2Client),
+    /// OAuth2 resource owner password grant
+    #[serde(rename = "oauth2_password")]
+    OAuth2Password {
+        #[serde(flatten)]
+        client: OAuth2Client,
+        username: String,
+        password: String,
+    },
+    /// AWS Signature Version 4, computed over the request as sent
+    #[serde(rename = "aws_sigv4")]
+    AwsSigV4(AwsSigV4),
+    /// HMAC of a string built from the request as sent, put in a header
+    Hmac(HmacAuth),
+}
+
+impl Auth {
+    /// Returns the fields that may hold `{{...}}` references.
+    pub fn strings(&self) -> Vec<&String> {
+        match self {
+            Auth::Basic(basic) | Auth::Digest(basic) => vec![&basic.username, &basic.password],
+            Auth::Bearer { token } => vec![token],
+            Auth::OAuth2ClientCredentials(client) => client.strings(),
+            Auth::OAuth2Password { client, username, password } => {
+                let mut strings = client.strings();
+                strings.extend([username, password]);
+                strings
+            }
+            Auth::AwsSigV4(aws) => {
+                let mut strings = vec![
+                    &aws.region,
+                    &aws.service,
+                    &aws.access_key_id,
+                    &aws.secret_access_key,
+                ];
+                strings.extend(&aws.session_token);
+                strings
+            }
+            Auth::Hmac(hmac) => vec![&hmac.secret],
+        }
+    }
+
+    pub fn strings_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Auth::Basic(basic) | Auth::Digest(basic) => {
+                vec![&mut basic.username, &mut basic.password]
+            }
+            Auth::Bearer { token } => vec![token],
+            Auth::OAuth2ClientCredentials(client) => client.strings_mut(),
+            Auth::OAuth2Password { client, username, password } => {
+                let mut strings = client.strings_mut();
+                strings.extend([username, password]);
+                strings
+            }
+            Auth::AwsSigV4(aws) => {
+                let mut strings = vec![
+                    &mut aws.region,
+                    &mut aws.service,
+                    &mut aws.access_key_id,
+                    &mut aws.secret_access_key,
+                ];
+                strings.extend(&mut aws.session_token);
+                strings
+            }
+            Auth::Hmac(hmac) => vec![&mut hmac.secret],
+        }
+    }
+}
+
+pub fn deserialize_auth<'de, D>(deserializer: D) -> Result<Option<Auth>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // `auth` without a `type` holds basic credentials
+    Ok(Option::<AuthSchema>::deserialize(deserializer)?.map(|auth| match auth {
+        AuthSchema::Typed(auth) => auth,
+        AuthSchema::Basic(basic) => Auth::Basic(basic),
+    }))
+}
+
+// Schema counterpart of `deserialize_auth`
+/// Credentials with a `type`, or basic credentials
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "Authentication")]
+#[serde(untagged)]
+enum AuthSchema {
+    Typed(Auth),
+    Basic(BasicAuth),
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 pub struct BasicAuth {
     pub username: String,
     pub password: String,
 }
 
+/// An OAuth2 client requesting tokens. Tokens are fetched once per run for every
+/// task using the same client, and fetched again when they expire.
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+pub struct OAuth2Client {
+    /// Token endpoint
+    pub token_url: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    /// Scopes requested for the token
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl OAuth2Client {
+    fn strings(&self) -> Vec<&String> {
+        let mut strings = vec![&self.token_url, &self.client_id];
+        strings.extend(&self.client_secret);
+        strings.extend(&self.scopes);
+        strings
+    }
+
+    fn strings_mut(&mut self) -> Vec<&mut String> {
+        let mut strings = vec![&mut self.token_url, &mut self.client_id];
+        strings.extend(&mut self.client_secret);
+        strings.extend(&mut self.scopes);
+        strings
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct AwsSigV4 {
+    /// Region, e.g. `us-east-1`
+    pub region: String,
+    /// Service, e.g. `s3` or `execute-api`
+    pub service: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Session token of temporary credentials
+    #[serde(default)]
+    pub session_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct HmacAuth {
+    pub secret: String,
+    #[serde(default)]
+    pub algorithm: HmacAlgorithm,
+    /// String to sign. `{method}`, `{url}`, `{path}` (with the query), `{query}`, `{host}`,
+    /// `{body}`, `{body_sha256}`, `{timestamp}` (Unix seconds) and `{header:Name}` are
+    /// replaced with the values of the request.
+    #[serde(default = "default_hmac_template")]
+    pub template: String,
+    /// Header the signature is sent in
+    #[serde(default = "default_hmac_header")]
+    pub header: String,
+    /// Text put before the signature, e.g. `sha256=`
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub encoding: SignatureEncoding,
+    /// Header the timestamp is sent in, so the server can check the signature
+    #[serde(default)]
+    pub timestamp_header: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HmacAlgorithm {
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+pub fn default_hmac_template() -> String {
+    "{method}\n{path}\n{timestamp}\n{body_sha256}".to_string()
+}
+
+pub fn default_hmac_header() -> String {
+    "X-Signature".to_string()
+}
+
 pub fn default_retries() -> u32 {
     1
 }
@@ -90,9 +985,149 @@ pub fn default_retry_delay() -> u64 {
     5
 }
 
+pub fn default_poll_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+pub fn default_poll_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+
+pub fn default_max_concurrency() -> usize {
+    4
+}
+
+pub fn default_foreach_concurrency() -> usize {
+    1
+}
+
 #[derive(Clone)]
 pub struct RegisteredResponse {
     pub json: Value,
     #[allow(dead_code)]
     pub text: String,
 }
+
+/// Adds the serde `alias` of a field to the schema, which schemars leaves out.
+fn alias(field: &'static str, alias: &'static str) -> impl Fn(&mut schemars::Schema) {
+    move |schema| {
+        let Some(properties) = schema.get_mut("properties").and_then(Value::as_object_mut) else {
+            return;
+        };
+        if let Some(mut property) = properties.get(field).cloned() {
+            property["description"] = format!("Alias of `{}`", field).into();
+            properties.insert(alias.to_string(), property);
+        }
+    }
+}
+
+/// JSON Schema of the workflow file format, for editors and YAML language servers
+pub fn workflow_schema() -> String {
+    let schema = schemars::schema_for!(Workflow);
+    serde_json::to_string_pretty(&schema).unwrap_or_default() + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+        assert!(parse_duration("fast").is_err());
+        assert!(parse_duration("3d").is_err());
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let retry: Retry = serde_yaml::from_str(
+            r#"
+            delay: 100ms
+            backoff: exponential
+            max_delay: 1s
+            retry_on: [429, 5xx, connection]
+        "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            retry.retry_on,
+            vec![RetryOn::Status(429), RetryOn::StatusClass(5), RetryOn::Connection]
+        );
+        let base = retry.delay.unwrap();
+        assert_eq!(retry.backoff_delay(base, 1), Duration::from_millis(100));
+        assert_eq!(retry.backoff_delay(base, 3), Duration::from_millis(400));
+        assert_eq!(retry.backoff_delay(base, 5), Duration::from_secs(1));
+        assert!(serde_yaml::from_str::<Retry>("retry_on: [sometimes]").is_err());
+    }
+
+    #[test]
+    fn test_tls_settings() {
+        let defaults: Tls = serde_yaml::from_str("{ ca_cert: ca.pem, min_version: 1.2 }").unwrap();
+        let tls: Tls = serde_yaml::from_str("{ min_version: '1.0', server_name: api.internal }")
+            .unwrap();
+        let tls = tls.or(&defaults);
+
+        assert_eq!(tls.ca_cert.as_deref(), Some("ca.pem"));
+        assert_eq!(tls.min_version, Some(TlsVersion::V1_0));
+        assert_eq!(tls.server_name.as_deref(), Some("api.internal"));
+        assert_eq!(defaults.min_version, Some(TlsVersion::V1_2));
+        assert!(serde_yaml::from_str::<Tls>("min_version: 1.3").is_err());
+        assert!(serde_yaml::from_str::<Tls>("min_version: 2").is_err());
+    }
+
+    #[test]
+    fn test_expectations() {
+        let expect: Vec<Expect> = serde_yaml::from_str(
+            r#"
+            - { type: Status, code: [201, 2xx] }
+            - { type: Header, name: Content-Type, matches: json }
+            - { type: ResponseTime, max_ms: 500 }
+            - { type: BodySize, lte: 1024 }
+        "#,
+        )
+        .unwrap();
+
+        let Expect::Status { code } = &expect[0] else {
+            panic!("expected a status expectation");
+        };
+        assert!(code.matches(201) && code.matches(204) && !code.matches(301));
+        assert_eq!(code.to_string(), "201 or 2xx");
+        assert!(matches!(
+            &expect[1],
+            Expect::Header { name, assertion } if name == "Content-Type" && assertion.matches.is_some()
+        ));
+        assert!(matches!(expect[2], Expect::ResponseTime { max_ms: 500 }));
+        assert!(expect[3].assertion().is_some_and(|a| a.lte.is_some()));
+        assert!(serde_yaml::from_str::<Expect>("{ type: Status, code: 6xx }").is_err());
+    }
+
+    #[test]
+    fn test_published_schema_is_up_to_date() {
+        let published = include_str!("../workflow.schema.json");
+        assert!(
+            published == workflow_schema(),
+            "workflow.schema.json is stale, regenerate it with `crabflow schema > workflow.schema.json`"
+        );
+    }
+
+    #[test]
+    fn test_schema_accepts_aliases() {
+        let schema: Value = serde_json::from_str(&workflow_schema()).unwrap();
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        let workflow: Value = serde_yaml::from_str(
+            r#"
+            name: aliases
+            tasks:
+              - { name: t, type: http, method: GET, url: "http://localhost/{{item}}", loop: [1, 2],
+                  expect: [{ type: JsonPath, path: state, one_of: [ok, done] }] }
+        "#,
+        )
+        .unwrap();
+
+        assert!(validator.is_valid(&workflow), "{:?}", validator.validate(&workflow));
+    }
+}
@@ -0,0 +1,861 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+
+use regex::Regex;
+
+use crate::auth;
+use crate::client;
+use crate::expr::parse_expression;
+use crate::graph::{TaskGraph, referenced_names, references, task_strings};
+use crate::jsonpath::Path;
+use crate::resolve::{LOOP_VARIABLES, Segment, parse_condition, parse_template, split_reference};
+use crate::types::{self, Auth, Expect, Task, Workflow};
+use crate::vars::VARS;
+
+const HTTP_METHODS: [&str; 9] = [
+    "GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS", "TRACE", "CONNECT",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found in a workflow file before anything is executed.
+#[derive(Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    pub line: Option<usize>,
+    pub task: Option<String>,
+    pub message: String,
+}
+
+impl Issue {
+    fn for_task(tasks: &[Task], lines: &[Option<usize>], index: usize, message: String) -> Self {
+        Issue {
+            severity: Severity::Error,
+            line: lines.get(index).copied().flatten(),
+            task: Some(tasks[index].name.clone()),
+            message,
+        }
+    }
+
+    fn warning(mut self) -> Self {
+        self.severity = Severity::Warning;
+        self
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if let Some(task) = &self.task {
+            write!(f, "task `{}`: ", task)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Finds the 1-based line declaring each task of `Workflow::all_tasks` by scanning the
+/// raw YAML for its `name:`.
+pub fn task_lines(yaml: &str, wf: &Workflow) -> Vec<Option<usize>> {
+    let mut lines = section_lines(yaml, "tasks:", &wf.tasks);
+    lines.extend(section_lines(yaml, "finally:", &wf.finally));
+    lines
+}
+
+fn section_lines(yaml: &str, section: &str, tasks: &[Task]) -> Vec<Option<usize>> {
+    let lines: Vec<&str> = yaml.lines().collect();
+    let mut cursor = lines
+        .iter()
+        .position(|l| l.starts_with(section))
+        .map_or(0, |i| i + 1);
+
+    tasks
+        .iter()
+        .map(|task| {
+            let found = lines[cursor..]
+                .iter()
+                .position(|l| declares_name(l, &task.name))?;
+            cursor += found + 1;
+            Some(cursor)
+        })
+        .collect()
+}
+
+fn declares_name(line: &str, name: &str) -> bool {
+    line.match_indices("name:").any(|(pos, key)| {
+        let preceded_by_key = line[..pos]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '-');
+        if preceded_by_key {
+            return false;
+        }
+        let value = line[pos + key.len()..]
+            .trim_start()
+            .trim_start_matches(['"', '\'']);
+        value.strip_prefix(name).is_some_and(|rest| {
+            rest.trim_start_matches(['"', '\''])
+                .trim_start()
+                .chars()
+                .next()
+                .is_none_or(|c| c == ',' || c == '}' || c == '#')
+        })
+    })
+}
+
+/// Checks task names and the dependency graph: duplicate names, unknown `depends_on`
+/// entries, dependency cycles and references to responses registered by tasks that
+/// are not guaranteed to have run first.
+pub fn check_graph(tasks: &[Task], lines: &[Option<usize>]) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    let mut by_name: HashMap<&str, usize> = HashMap::new();
+    for (index, task) in tasks.iter().enumerate() {
+        if let Some(&first) = by_name.get(task.name.as_str()) {
+            let location = match lines.get(first).copied().flatten() {
+                Some(line) => format!(" (first declared on line {})", line),
+                None => String::new(),
+            };
+            issues.push(Issue::for_task(
+                tasks,
+                lines,
+                index,
+                format!("duplicate task name{}", location),
+            ));
+        } else {
+            by_name.insert(&task.name, index);
+        }
+    }
+
+    for (index, task) in tasks.iter().enumerate() {
+        for dep in &task.depends_on {
+            if !by_name.contains_key(dep.as_str()) {
+                issues.push(Issue::for_task(
+                    tasks,
+                    lines,
+                    index,
+                    format!("depends on unknown task `{}`", dep),
+                ));
+            }
+        }
+    }
+
+    let graph = TaskGraph::build(tasks);
+    for cycle in find_cycles(&graph) {
+        let mut path: Vec<&str> = cycle.iter().map(|&i| tasks[i].name.as_str()).collect();
+        path.push(path[0]);
+        issues.push(Issue::for_task(
+            tasks,
+            lines,
+            cycle[0],
+            format!("dependency cycle: {}", path.join(" -> ")),
+        ));
+    }
+
+    for (index, task) in tasks.iter().enumerate() {
+        let ancestors = graph.ancestors(index);
+        for name in referenced_names(task) {
+            let registrants: Vec<usize> = (0..tasks.len())
+                .filter(|&i| i != index && tasks[i].register.as_deref() == Some(name.as_str()))
+                .collect();
+            if !registrants.is_empty() && !registrants.iter().any(|&i| ancestors[i]) {
+                let registrant = &tasks[registrants[0]].name;
+                issues.push(Issue::for_task(
+                    tasks,
+                    lines,
+                    index,
+                    format!(
+                        "references `{}` before it is registered by task `{}`; add `{}` to depends_on",
+                        name, registrant, registrant
+                    ),
+                ));
+            }
+        }
+    }
+
+    issues.sort_by_key(|issue| issue.line);
+    issues
+}
+
+/// Returns each dependency cycle once, as task indexes in dependency order.
+fn find_cycles(graph: &TaskGraph) -> Vec<Vec<usize>> {
+    fn visit(
+        node: usize,
+        graph: &TaskGraph,
+        state: &mut [u8],
+        stack: &mut Vec<usize>,
+        cycles: &mut Vec<Vec<usize>>,
+    ) {
+        // 0 = unvisited, 1 = on the current path, 2 = fully explored
+        state[node] = 1;
+        stack.push(node);
+        for &dep in &graph.dependencies[node] {
+            match state[dep] {
+                0 => visit(dep, graph, state, stack, cycles),
+                1 => {
+                    let start = stack.iter().position(|&n| n == dep).unwrap_or(0);
+                    cycles.push(stack[start..].to_vec());
+                }
+                _ => {}
+            }
+        }
+        stack.pop();
+        state[node] = 2;
+    }
+
+    let mut state = vec![0; graph.dependencies.len()];
+    let mut stack = Vec::new();
+    let mut cycles = Vec::new();
+    for node in 0..graph.dependencies.len() {
+        if state[node] == 0 {
+            visit(node, graph, &mut state, &mut stack, &mut cycles);
+        }
+    }
+    cycles
+}
+
+/// Parses a workflow, also returning the paths of keys serde ignored (e.g. `tasks.2.retry`).
+pub fn parse_workflow(yaml: &str) -> Result<(Workflow, Vec<String>), serde_yaml::Error> {
+    let mut ignored = Vec::new();
+    let deserializer = serde_yaml::Deserializer::from_str(yaml);
+    let wf = serde_ignored::deserialize(deserializer, |path| ignored.push(path.to_string()))?;
+    Ok((wf, ignored))
+}
+
+/// Runs every check on a workflow file: unknown keys, the dependency graph and
+/// per-task linting.
+pub fn check_workflow(yaml: &str) -> Result<Vec<Issue>, serde_yaml::Error> {
+    let (wf, ignored) = parse_workflow(yaml)?;
+    let tasks = wf.all_tasks();
+    let lines = task_lines(yaml, &wf);
+
+    let mut issues = unknown_keys(yaml, &wf, &lines, &ignored);
+    issues.extend(check_graph(&tasks, &lines));
+    issues.extend(lint(&tasks, &lines));
+    issues.extend(check_clients(yaml, &wf, &tasks, &lines));
+    issues.sort_by_key(|issue| issue.line);
+    Ok(issues)
+}
+
+/// Prints every issue found in a workflow file and fails if any of them is an error.
+pub fn report(workflow_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let yaml = fs::read_to_string(workflow_path)?;
+    let issues = check_workflow(&yaml).map_err(|e| format!("{}: {}", workflow_path, e))?;
+
+    for issue in &issues {
+        println!("{}: {}: {}", workflow_path, issue.severity, issue);
+    }
+
+    let errors = issues
+        .iter()
+        .filter(|i| i.severity == Severity::Error)
+        .count();
+    let warnings = issues.len() - errors;
+    if errors > 0 {
+        return Err(format!(
+            "{}: {} error(s), {} warning(s)",
+            workflow_path, errors, warnings
+        )
+        .into());
+    }
+    println!("{}: valid ({} warning(s))", workflow_path, warnings);
+    Ok(())
+}
+
+/// Turns ignored key paths into issues located at their task or top-level key.
+/// `lines` are the task lines of `Workflow::all_tasks`.
+pub fn unknown_keys(
+    yaml: &str,
+    wf: &Workflow,
+    lines: &[Option<usize>],
+    ignored: &[String],
+) -> Vec<Issue> {
+    let tasks = wf.all_tasks();
+    ignored
+        .iter()
+        .map(|path| {
+            let parts: Vec<&str> = path.split('.').collect();
+            let index = match (parts.first(), parts.get(1).and_then(|i| i.parse::<usize>().ok())) {
+                (Some(&"tasks"), Some(index)) if index < wf.tasks.len() => Some(index),
+                (Some(&"finally"), Some(index)) if index < wf.finally.len() => {
+                    Some(wf.tasks.len() + index)
+                }
+                _ => None,
+            };
+            match (index, parts.get(2..)) {
+                (Some(index), Some(key)) if !key.is_empty() => {
+                    Issue::for_task(&tasks, lines, index, format!("unknown key `{}`", key.join(".")))
+                }
+                _ => Issue {
+                    severity: Severity::Error,
+                    line: yaml
+                        .lines()
+                        .position(|l| l.starts_with(&format!("{}:", parts[0])))
+                        .map(|i| i + 1),
+                    task: None,
+                    message: format!("unknown key `{}`", path),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Checks that tasks select client profiles the workflow defines, and the proxies of
+/// the profiles and of `defaults`.
+fn check_clients(yaml: &str, wf: &Workflow, tasks: &[Task], lines: &[Option<usize>]) -> Vec<Issue> {
+    let mut names: Vec<&str> = wf.clients.keys().map(String::as_str).collect();
+    names.sort_unstable();
+
+    let profiles = names
+        .iter()
+        .map(|name| ("clients:", format!("client `{}`", name), &wf.clients[*name]));
+    let mut issues: Vec<Issue> = std::iter::once(("defaults:", "`defaults`".to_string(), &wf.defaults))
+        .chain(profiles)
+        .filter_map(|(section, owner, defaults)| {
+            let message = check_proxy(defaults.proxy.as_deref()?)?;
+            Some(Issue {
+                severity: Severity::Error,
+                line: yaml.lines().position(|l| l.starts_with(section)).map(|i| i + 1),
+                task: None,
+                message: format!("{}: {}", owner, message),
+            })
+        })
+        .collect();
+
+    issues.extend(tasks.iter()
+        .enumerate()
+        .filter_map(|(index, task)| {
+            let name = task.client.as_deref()?;
+            if wf.clients.contains_key(name) {
+                return None;
+            }
+            let defined = if names.is_empty() {
+                "none is defined".to_string()
+            } else {
+                format!("expected one of `{}`", names.join("`, `"))
+            };
+            let message = format!("unknown client `{}` ({})", name, defined);
+            Some(Issue::for_task(tasks, lines, index, message))
+        }));
+    issues
+}
+
+/// Checks that a proxy URL parses, unless it is only known once rendered.
+fn check_proxy(proxy: &str) -> Option<String> {
+    if proxy.contains("{{") {
+        return None;
+    }
+    let e = reqwest::Proxy::all(proxy).err()?;
+    Some(format!("invalid `proxy` `{}`: {}", proxy, client::cause(&e)))
+}
+
+/// Checks each task against its `type`: required and meaningless fields, HTTP methods,
+/// the syntax of `{{...}}` references and whether referenced names are ever registered.
+pub fn lint(tasks: &[Task], lines: &[Option<usize>]) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    let registered: HashSet<&str> = tasks.iter().filter_map(|t| t.register.as_deref()).collect();
+    let mut used: HashSet<String> = HashSet::new();
+    let mut shows_everything = false;
+
+    for (index, task) in tasks.iter().enumerate() {
+        let issue = |message: String| Issue::for_task(tasks, lines, index, message);
+
+        match task.kind.as_str() {
+            "http" => {
+                if task.url.is_empty() {
+                    issues.push(issue("http task requires `url`".to_string()));
+                }
+                if task.method.is_empty() {
+                    issues.push(issue("http task requires `method`".to_string()));
+                } else if !HTTP_METHODS.contains(&task.method.as_str()) {
+                    let hint = if HTTP_METHODS.contains(&task.method.to_uppercase().as_str()) {
+                        format!(" (methods are case-sensitive, use `{}`)", task.method.to_uppercase())
+                    } else {
+                        String::new()
+                    };
+                    issues.push(issue(format!("invalid method `{}`{}", task.method, hint)));
+                }
+                if task.variables.is_some() {
+                    issues.push(
+                        issue("`variables` only applies to display tasks".to_string()).warning(),
+                    );
+                }
+            }
+            "display" => {
+                let ignored_fields = [
+                    ("method", !task.method.is_empty()),
+                    ("url", !task.url.is_empty()),
+                    ("headers", !task.headers.is_empty()),
+                    ("query", !task.query.is_empty()),
+                    ("body", task.body.is_some()),
+                    ("body_type", task.body_type.is_some()),
+                    ("expect", !task.expect.is_empty()),
+                    ("fail_fast", task.fail_fast),
+                    ("register", task.register.is_some()),
+                    ("auth", task.auth.is_some()),
+                    ("save_as", task.save_as.is_some()),
+                    ("foreach", task.foreach.is_some()),
+                    ("poll", task.poll.is_some()),
+                    ("retry", task.retry.is_some()),
+                    ("timeout", task.timeout.is_some()),
+                    ("connect_timeout", task.connect_timeout.is_some()),
+                    ("read_timeout", task.read_timeout.is_some()),
+                    ("tls", task.tls.is_some()),
+                    ("proxy", task.proxy.is_some()),
+                    ("no_proxy", task.no_proxy.is_some()),
+                    ("max_redirects", task.max_redirects.is_some()),
+                    ("client", task.client.is_some()),
+                    ("continue_on_error", task.continue_on_error),
+                    ("vars", !task.vars.is_empty()),
+                ];
+                for (field, set) in ignored_fields {
+                    if set {
+                        issues.push(
+                            issue(format!("`{}` has no effect on display tasks", field)).warning(),
+                        );
+                    }
+                }
+                match &task.variables {
+                    Some(variables) => {
+                        for name in variables {
+                            if !registered.contains(name.as_str()) {
+                                issues.push(issue(format!(
+                                    "displays `{}`, which no task registers",
+                                    name
+                                )));
+                            }
+                            used.insert(name.clone());
+                        }
+                    }
+                    None => shows_everything = true,
+                }
+            }
+            other => {
+                issues.push(issue(format!(
+                    "unknown task type `{}` (expected `http` or `display`)",
+                    other
+                )));
+            }
+        }
+
+        if let Some(condition) = &task.when {
+            if let Err(e) = parse_condition(condition) {
+                issues.push(issue(format!("invalid `when` condition `{}`: {}", condition, e)));
+            }
+        }
+
+        let until = task.poll.iter().flat_map(|poll| &poll.until);
+        for expect in task.expect.iter().chain(until) {
+            let kind = match expect {
+                Expect::JsonPath { path, .. } => {
+                    if let Err(e) = Path::parse(path) {
+                        issues.push(issue(format!("`JsonPath` expectation: {}", e)));
+                    }
+                    "JsonPath"
+                }
+                Expect::Header { name, .. } => {
+                    if reqwest::header::HeaderName::try_from(name.as_str()).is_err() {
+                        let message = format!("`Header` expectation: invalid header name `{}`", name);
+                        issues.push(issue(message));
+                    }
+                    "Header"
+                }
+                Expect::JsonSchema { schema, file } => {
+                    match (schema, file) {
+                        (Some(schema), None) => {
+                            if let Err(e) = jsonschema::validator_for(schema) {
+                                let message = format!("`JsonSchema` expectation: invalid schema: {}", e);
+                                issues.push(issue(message));
+                            }
+                        }
+                        (None, Some(_)) => {}
+                        _ => issues.push(issue(
+                            "`JsonSchema` expectation needs either `schema` or `file`".to_string(),
+                        )),
+                    }
+                    continue;
+                }
+                Expect::Raw { .. } => "Raw",
+                Expect::BodySize { .. } => "BodySize",
+                Expect::Status { .. } | Expect::ResponseTime { .. } => continue,
+            };
+            let Some(assertion) = expect.assertion() else {
+                continue;
+            };
+            if assertion.is_empty() {
+                issues.push(issue(format!(
+                    "`{}` expectation needs an operator such as `equals` or `contains`",
+                    kind
+                )));
+            }
+            if let Some(pattern) = assertion.matches.as_deref().filter(|p| !p.contains("{{")) {
+                if let Err(e) = Regex::new(pattern) {
+                    issues.push(issue(format!("`{}` expectation: invalid regex: {}", kind, e)));
+                }
+            }
+        }
+        if task.poll.as_ref().is_some_and(|poll| poll.until.is_empty()) {
+            issues.push(issue("`poll.until` needs at least one expectation".to_string()));
+        }
+
+        match &task.auth {
+            Some(Auth::OAuth2ClientCredentials(client)) if client.client_secret.is_none() => {
+                let message = "`oauth2_client_credentials` auth requires `client_secret`";
+                issues.push(issue(message.to_string()));
+            }
+            Some(Auth::Hmac(hmac)) => {
+                if let Err(e) = auth::parse_signing_template(&hmac.template) {
+                    issues.push(issue(e));
+                }
+                for name in std::iter::once(&hmac.header).chain(&hmac.timestamp_header) {
+                    if reqwest::header::HeaderName::try_from(name.as_str()).is_err() {
+                        issues.push(issue(format!("`hmac` auth: invalid header name `{}`", name)));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(message) = task.proxy.as_deref().and_then(check_proxy) {
+            issues.push(issue(message));
+        }
+
+        if let Some(tls) = &task.tls {
+            if tls.client_key.is_some() && tls.client_cert.is_none() {
+                issues.push(issue("`tls.client_key` requires `tls.client_cert`".to_string()));
+            }
+        }
+
+        if task.retry.as_ref().is_some_and(|retry| !(0.0..=1.0).contains(&retry.jitter)) {
+            issues.push(issue("`retry.jitter` must be between 0 and 1".to_string()));
+        }
+        if let Some(factor) = task.poll.as_ref().and_then(|poll| poll.backoff) {
+            if !types::valid_backoff(factor) {
+                issues.push(issue("`poll.backoff` must be at least 1".to_string()));
+            }
+        }
+
+        match &task.foreach {
+            None | Some(serde_yaml::Value::Sequence(_)) => {}
+            Some(serde_yaml::Value::String(s))
+                if matches!(parse_template(s).as_slice(), [Segment::Expression(_)]) => {}
+            Some(_) => issues.push(issue(
+                "`foreach` must be a list or a single `{{...}}` reference".to_string(),
+            )),
+        }
+        if let Some(name) = &task.register {
+            if LOOP_VARIABLES.contains(&name.as_str()) {
+                issues.push(issue(format!("`{}` is reserved for foreach loops", name)));
+            }
+            if name == VARS {
+                issues.push(issue(format!("`{}` is reserved for variables", name)));
+            }
+        }
+
+        for s in task_strings(task) {
+            if s.rfind("{{").is_some_and(|open| !s[open..].contains("}}")) {
+                issues.push(issue(format!("unterminated `{{{{` in `{}`", s)));
+            }
+            for segment in parse_template(s) {
+                let Segment::Expression(source) = segment else {
+                    continue;
+                };
+                if let Err(e) = parse_expression(source) {
+                    issues.push(issue(format!("invalid expression `{{{{{}}}}}`: {}", source, e)));
+                }
+            }
+            for reference in references(s) {
+                if let Err(e) = check_reference(&reference) {
+                    issues.push(issue(format!("malformed reference `{{{{{}}}}}`: {}", reference, e)));
+                }
+                let root = reference.split(['.', '[']).next().unwrap_or_default();
+                if LOOP_VARIABLES.contains(&root) && task.foreach.is_none() {
+                    issues.push(issue(format!(
+                        "`{{{{{}}}}}` is only available in foreach tasks",
+                        reference
+                    )));
+                }
+            }
+        }
+
+        for name in referenced_names(task) {
+            if !registered.contains(name.as_str()) {
+                issues.push(issue(format!("references `{}`, which no task registers", name)));
+            }
+            used.insert(name);
+        }
+    }
+
+    if !shows_everything {
+        for (index, task) in tasks.iter().enumerate() {
+            if let Some(name) = &task.register {
+                if !used.contains(name) {
+                    issues.push(
+                        Issue::for_task(
+                            tasks,
+                            lines,
+                            index,
+                            format!("registers `{}`, which nothing uses", name),
+                        )
+                        .warning(),
+                    );
+                }
+            }
+        }
+    }
+
+    issues
+}
+
+/// Checks the syntax of a reference: `env.NAME`, or `vars.name`, `item` or `name.json`
+/// followed by a path.
+fn check_reference(reference: &str) -> Result<(), String> {
+    let (root, path) = split_reference(reference)?;
+    if root == "env" {
+        if path.is_empty() || !path.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("`{}` is not a valid environment variable name", path));
+        }
+        return Ok(());
+    }
+    Path::parse(path).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Workflow;
+
+    fn check(yaml: &str) -> Vec<String> {
+        let wf: Workflow = serde_yaml::from_str(yaml).unwrap();
+        let lines = task_lines(yaml, &wf);
+        check_graph(&wf.all_tasks(), &lines)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_workflow() {
+        let issues = check(
+            r#"
+name: ok
+tasks:
+  - name: login
+    type: http
+    register: session
+  - name: profile
+    type: http
+    depends_on: [login]
+    url: "http://localhost/{{session.json.id}}"
+"#,
+        );
+
+        assert!(issues.is_empty(), "{:?}", issues);
+    }
+
+    #[test]
+    fn test_reports_all_problems_with_lines() {
+        let issues = check(
+            r#"
+name: broken
+tasks:
+  - name: a
+    type: http
+    depends_on: [c]
+  - name: b
+    type: http
+    depends_on: [missing]
+  - name: c
+    type: http
+    depends_on: [a]
+  - name: b
+    type: http
+"#,
+        );
+
+        assert_eq!(
+            issues,
+            vec![
+                "line 4: task `a`: dependency cycle: a -> c -> a",
+                "line 7: task `b`: depends on unknown task `missing`",
+                "line 13: task `b`: duplicate task name (first declared on line 7)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forward_reference() {
+        let issues = check(
+            r#"
+name: forward
+tasks:
+  - { name: use, type: http, url: "http://localhost/{{later.json.id}}" }
+  - { name: create, type: http, register: later }
+"#,
+        );
+
+        assert_eq!(
+            issues,
+            vec![
+                "line 4: task `use`: references `later` before it is registered by task `create`; add `create` to depends_on"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_workflow() {
+        let yaml = r#"
+name: lint
+retries: 3
+tasks:
+  - name: create
+    type: http
+    method: post
+    url: "http://localhost/{{env.API}}"
+    register: created
+    retires: 2
+  - name: fetch
+    type: http
+    url: "http://localhost/{{created.id}}/{{missing.json.x}}"
+    headers:
+      X-Id: "{{created.json.items[x]}}"
+  - name: show
+    type: display
+    url: "http://localhost"
+    variables: [nothing]
+  - name: other
+    type: grpc
+    url: "http://localhost/{{item.id}}"
+  - name: signed
+    type: http
+    method: GET
+    url: "http://localhost"
+    auth:
+      type: hmac
+      secret: s3cr3t
+      template: "{method} {verb}"
+"#;
+        let issues: Vec<String> = check_workflow(yaml)
+            .unwrap()
+            .iter()
+            .map(|i| format!("{}: {}", i.severity, i))
+            .collect();
+
+        assert_eq!(
+            issues,
+            vec![
+                "error: line 3: unknown key `retries`",
+                "error: line 5: task `create`: unknown key `retires`",
+                "error: line 5: task `create`: invalid method `post` (methods are case-sensitive, use `POST`)",
+                "error: line 11: task `fetch`: http task requires `method`",
+                "error: line 11: task `fetch`: malformed reference `{{created.id}}`: expected `created.json`, found `created.id`",
+                "error: line 11: task `fetch`: malformed reference `{{created.json.items[x]}}`: invalid path `items[x]`: at position 10, parser error",
+                "error: line 11: task `fetch`: references `missing`, which no task registers",
+                "warning: line 16: task `show`: `url` has no effect on display tasks",
+                "error: line 16: task `show`: displays `nothing`, which no task registers",
+                "error: line 20: task `other`: unknown task type `grpc` (expected `http` or `display`)",
+                "error: line 20: task `other`: `{{item.id}}` is only available in foreach tasks",
+                "error: line 23: task `signed`: unknown placeholder `{verb}` in HMAC template `{method} {verb}`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_task_lines_skip_similar_keys() {
+        let yaml = r#"name: a
+tasks:
+  - type: http
+    auth: { username: b, password: x }
+    name: a
+  - name: "b"
+    type: http
+"#;
+        let wf: Workflow = serde_yaml::from_str(yaml).unwrap();
+
+        assert_eq!(task_lines(yaml, &wf), vec![Some(5), Some(6)]);
+    }
+
+    #[test]
+    fn test_finally_tasks_are_checked() {
+        let yaml = r#"name: a
+finally:
+  - name: teardown
+    type: http
+    method: DELETE
+    url: "http://localhost/{{session.json.id}}"
+    retires: 1
+tasks:
+  - name: login
+    type: http
+    method: POST
+    url: "http://localhost/login"
+    register: session
+"#;
+        let issues: Vec<String> = check_workflow(yaml)
+            .unwrap()
+            .iter()
+            .map(|issue| format!("{}: {}", issue.severity, issue))
+            .collect();
+
+        assert_eq!(issues, vec!["error: line 3: task `teardown`: unknown key `retires`"]);
+    }
+
+    #[test]
+    fn test_client_profiles() {
+        let yaml = r#"name: a
+clients:
+  internal:
+    proxy: "http://proxy.internal:3128"
+    no_proxy: [localhost]
+    headers:
+      X-Team: qa
+  broken:
+    proxy: "not a proxy"
+defaults:
+  proxy: "http://proxy:port"
+tasks:
+  - name: known
+    type: http
+    method: GET
+    url: "http://localhost/"
+    client: internal
+  - name: unknown
+    type: http
+    method: GET
+    url: "http://localhost/"
+    client: external
+    proxy: "not a proxy"
+"#;
+        let issues: Vec<String> = check_workflow(yaml)
+            .unwrap()
+            .iter()
+            .map(|issue| format!("{}: {}", issue.severity, issue))
+            .collect();
+
+        assert_eq!(
+            issues,
+            vec![
+                "error: line 2: client `broken`: invalid `proxy` `not a proxy`: relative URL without a base",
+                "error: line 10: `defaults`: invalid `proxy` `http://proxy:port`: invalid port number",
+                "error: line 18: task `unknown`: invalid `proxy` `not a proxy`: relative URL without a base",
+                "error: line 18: task `unknown`: unknown client `external` (expected one of `broken`, `internal`)",
+            ]
+        );
+    }
+}
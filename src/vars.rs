@@ -0,0 +1,250 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::env;
+use crate::expr::parse_expression;
+use crate::graph::{collect_yaml_strings, references, task_strings};
+use crate::resolve::{Segment, parse_template, resolve_references};
+use crate::types::{RegisteredResponse, Task, Workflow};
+
+/// Registry name holding the variables, referenced as `{{vars.name}}`.
+pub const VARS: &str = "vars";
+
+/// Parses a `--var KEY=VALUE` argument. The value is read as YAML, so `3` is a number,
+/// `true` a boolean and `[1, 2]` a list.
+pub fn parse_assignment(s: &str) -> Result<(String, Value), String> {
+    let (key, raw) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{}`", s))?;
+    if key.is_empty() {
+        return Err(format!("missing variable name in `{}`", s));
+    }
+    let value = match serde_yaml::from_str(raw) {
+        Ok(Value::Null) | Err(_) => Value::String(raw.to_string()),
+        Ok(value) => value,
+    };
+    Ok((key.to_string(), value))
+}
+
+/// Reads a `--vars-file`: a YAML mapping of variable names to values.
+pub fn load_file(path: &str) -> Result<Map<String, Value>, Box<dyn std::error::Error>> {
+    let yaml = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let vars: Option<Map<String, Value>> =
+        serde_yaml::from_str(&yaml).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(vars.unwrap_or_default())
+}
+
+/// Resolves the templates in `vars` against the registry and layers the result over
+/// the variables already registered, then `overrides` over both. Variables can use
+/// each other and the overrides; one using its own name gets the value it replaces.
+pub fn layer(
+    registry: &HashMap<String, RegisteredResponse>,
+    vars: &HashMap<String, serde_yaml::Value>,
+    overrides: &Map<String, Value>,
+) -> Result<Map<String, Value>, String> {
+    let mut layered = match registry.get(VARS) {
+        Some(RegisteredResponse { json: Value::Object(current), .. }) => current.clone(),
+        _ => Map::new(),
+    };
+    layered.extend(overrides.clone());
+
+    // Overridden variables are never used, the others are resolved after those they use
+    let mut pending: Vec<&String> =
+        vars.keys().filter(|name| !overrides.contains_key(*name)).collect();
+    pending.sort_unstable();
+    let mut scoped = registry.clone();
+    while !pending.is_empty() {
+        let ready = |name: &&String| {
+            let used = var_references(&vars[*name]);
+            used.iter().all(|used| used == *name || !pending.contains(&used))
+        };
+        let Some(index) = pending.iter().position(ready) else {
+            let names: Vec<String> = pending.iter().map(|name| format!("`{}`", name)).collect();
+            return Err(format!("variables {} use each other", names.join(", ")));
+        };
+        let name = pending.remove(index);
+        register(&mut scoped, layered.clone());
+        let mut value = vars[name].clone();
+        resolve_references(&mut value, &scoped)
+            .map_err(|e| format!("variable `{}`: {}", name, e))?;
+        let value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+        layered.insert(name.clone(), value);
+    }
+    Ok(layered)
+}
+
+/// The names of the variables a value references through `{{vars.name}}`.
+fn var_references(value: &serde_yaml::Value) -> Vec<String> {
+    let mut strings = Vec::new();
+    collect_yaml_strings(value, &mut strings);
+    strings
+        .into_iter()
+        .flat_map(references)
+        .filter_map(|reference| {
+            let name = reference.strip_prefix("vars.")?.split(['.', '[']).next()?;
+            Some(name.to_string())
+        })
+        .collect()
+}
+
+/// Registers variables for `{{vars.name}}` lookups.
+pub fn register(registry: &mut HashMap<String, RegisteredResponse>, vars: Map<String, Value>) {
+    registry.insert(
+        VARS.to_string(),
+        RegisteredResponse {
+            json: Value::Object(vars),
+            text: String::new(),
+        },
+    );
+}
+
+/// Lists the `env.NAME` and `vars.name` references of a workflow that resolve to
+/// nothing, leaving out those with a `default`.
+pub fn missing(wf: &Workflow, overrides: &Map<String, Value>) -> Vec<String> {
+    let mut workflow_strings = Vec::new();
+    for value in wf.vars.values() {
+        collect_yaml_strings(value, &mut workflow_strings);
+    }
+    let tasks = wf.all_tasks();
+    let scopes = std::iter::once((None, workflow_strings))
+        .chain(tasks.iter().map(|task| (Some(task), task_strings(task))));
+
+    let mut missing = Vec::new();
+    for (task, strings) in scopes {
+        for s in strings {
+            for segment in parse_template(s) {
+                let Segment::Expression(source) = segment else {
+                    continue;
+                };
+                let Ok(expr) = parse_expression(source) else {
+                    continue;
+                };
+                for reference in expr.required_references() {
+                    if let Some(name) = undefined(reference, wf, task, overrides) {
+                        if !missing.contains(&name) {
+                            missing.push(name);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    missing
+}
+
+fn undefined(
+    reference: &str,
+    wf: &Workflow,
+    task: Option<&Task>,
+    overrides: &Map<String, Value>,
+) -> Option<String> {
+    if let Some(name) = reference.strip_prefix("env.") {
+        return env::var(name).is_none().then(|| format!("env.{}", name));
+    }
+    let name = reference
+        .strip_prefix("vars.")?
+        .split(['.', '['])
+        .next()
+        .unwrap_or_default();
+    let defined = overrides.contains_key(name)
+        || wf.vars.contains_key(name)
+        || task.is_some_and(|task| task.vars.contains_key(name))
+        || env::var(name).is_some();
+    (!defined).then(|| format!("vars.{}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_assignment() {
+        assert_eq!(parse_assignment("count=3").unwrap(), ("count".to_string(), json!(3)));
+        assert_eq!(parse_assignment("ids=[1, 2]").unwrap(), ("ids".to_string(), json!([1, 2])));
+        assert_eq!(parse_assignment("url=a=b").unwrap(), ("url".to_string(), json!("a=b")));
+        assert_eq!(parse_assignment("empty=").unwrap(), ("empty".to_string(), json!("")));
+        assert!(parse_assignment("novalue").is_err());
+        assert!(parse_assignment("=1").is_err());
+    }
+
+    #[test]
+    fn test_precedence() {
+        let mut registry = HashMap::new();
+        let workflow: HashMap<String, serde_yaml::Value> =
+            serde_yaml::from_str("{ region: eu, stage: dev, size: 1 }").unwrap();
+        let task: HashMap<String, serde_yaml::Value> =
+            serde_yaml::from_str("{ stage: test, label: '{{vars.region}}-{{vars.size}}' }").unwrap();
+        let mut cli = Map::new();
+        cli.insert("region".to_string(), json!("us"));
+
+        let base = layer(&registry, &workflow, &cli).unwrap();
+        register(&mut registry, base);
+        let scoped = layer(&registry, &task, &cli).unwrap();
+
+        assert_eq!(
+            Value::Object(scoped),
+            json!({ "region": "us", "stage": "test", "size": 1, "label": "us-1" })
+        );
+    }
+
+    #[test]
+    fn test_vars_use_each_other() {
+        let registry = HashMap::new();
+        let workflow: HashMap<String, serde_yaml::Value> = serde_yaml::from_str(
+            "{ url: '{{vars.base}}/{{vars.version}}', base: 'https://{{vars.host}}', host: api }",
+        )
+        .unwrap();
+        let mut cli = Map::new();
+        cli.insert("host".to_string(), json!("staging"));
+        cli.insert("version".to_string(), json!("v2"));
+
+        let vars = layer(&registry, &workflow, &cli).unwrap();
+        assert_eq!(vars["url"], json!("https://staging/v2"));
+
+        // A task variable can extend the workflow one it replaces
+        let mut registry = HashMap::new();
+        register(&mut registry, vars);
+        let task: HashMap<String, serde_yaml::Value> =
+            serde_yaml::from_str("{ url: '{{vars.url}}/users' }").unwrap();
+        let scoped = layer(&registry, &task, &Map::new()).unwrap();
+        assert_eq!(scoped["url"], json!("https://staging/v2/users"));
+
+        let cycle: HashMap<String, serde_yaml::Value> =
+            serde_yaml::from_str("{ a: '{{vars.b}}', b: '{{vars.a}}' }").unwrap();
+        assert_eq!(
+            layer(&HashMap::new(), &cycle, &Map::new()).unwrap_err(),
+            "variables `a`, `b` use each other"
+        );
+    }
+
+    #[test]
+    fn test_missing() {
+        let wf: Workflow = serde_yaml::from_str(
+            r#"
+name: vars
+vars:
+  host: "{{env.CRABFLOW_TEST_UNSET_HOST}}"
+tasks:
+  - name: fetch
+    type: http
+    method: GET
+    url: "https://{{vars.host}}/{{vars.path}}/{{vars.version}}"
+    vars:
+      path: users
+    query:
+      page: "{{vars.page | default(1)}}"
+      token: "{{env.CRABFLOW_TEST_UNSET_TOKEN | default(env.CRABFLOW_TEST_UNSET_FALLBACK)}}"
+"#,
+        )
+        .unwrap();
+        let mut cli = Map::new();
+        cli.insert("version".to_string(), json!("v2"));
+
+        assert_eq!(
+            missing(&wf, &cli),
+            vec!["env.CRABFLOW_TEST_UNSET_HOST", "env.CRABFLOW_TEST_UNSET_FALLBACK"]
+        );
+    }
+}
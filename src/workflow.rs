@@ -1,15 +1,23 @@
-use log::info;
+use log::{debug, error, info, warn};
 use prettytable::{Table, row};
-use serde_json::Value;
-use std::collections::HashMap;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
+use std::path::Path;
+use tokio::task::JoinSet;
 
-use crate::env::EnvResolver;
-use crate::http::execute_task;
-use crate::types::{RegisteredResponse, Workflow};
+use crate::auth::Tokens;
+use crate::client::Clients;
+use crate::env;
+use crate::graph::TaskGraph;
+use crate::http::{UnmetExpectations, execute_task};
+use crate::resolve::{evaluate_condition, resolve_references};
+use crate::types::{Expect, RegisteredResponse, Task, TaskDefaults, Tls, Workflow};
+use crate::validate;
+use crate::vars::{self, VARS};
 
 fn display_registered_variables(registry: &HashMap<String, RegisteredResponse>) {
-    if registry.is_empty() {
+    if registry.keys().all(|name| name == VARS) {
         info!("No variables have been registered.");
         return;
     }
@@ -17,7 +25,7 @@ fn display_registered_variables(registry: &HashMap<String, RegisteredResponse>)
     let mut table = Table::new();
     table.add_row(row!["Variable Name", "Value"]);
 
-    for (name, response) in registry {
+    for (name, response) in registry.iter().filter(|(name, _)| *name != VARS) {
         let value = serde_json::to_string_pretty(&response.json)
             .unwrap_or_else(|_| "Error serializing value".to_string());
         table.add_row(row![name, value]);
@@ -48,57 +56,652 @@ fn display_specific_variables(
     table.printstd();
 }
 
+/// Runs a `foreach` task once per item, `foreach_concurrency` iterations at a time,
+/// and collects the responses into an array in item order.
+async fn execute_foreach(
+    task: &Task,
+    client: &reqwest::Client,
+    tokens: &Tokens,
+    registry: &HashMap<String, RegisteredResponse>,
+) -> Result<(Value, String), Box<dyn std::error::Error + Send + Sync>> {
+    let mut items = task.foreach.clone().unwrap_or_default();
+    resolve_references(&mut items, registry)
+        .map_err(|e| format!("Task `{}`: {}", task.name, e))?;
+    let items = match serde_json::to_value(items)? {
+        Value::Array(items) => items,
+        other => {
+            return Err(format!("Task `{}`: foreach expects a list, got {}", task.name, other).into());
+        }
+    };
+    info!("Task `{}` looping over {} item(s)", task.name, items.len());
+
+    let concurrency = task.foreach_concurrency.max(1);
+    let mut outputs = vec![Value::Null; items.len()];
+    let mut running = JoinSet::new();
+    let mut next = 0;
+    let mut failure: Option<Box<dyn std::error::Error + Send + Sync>> = None;
+
+    loop {
+        while failure.is_none() && next < items.len() && running.len() < concurrency {
+            let mut registry = registry.clone();
+            registry.insert(
+                "item".to_string(),
+                RegisteredResponse {
+                    json: items[next].clone(),
+                    text: String::new(),
+                },
+            );
+            registry.insert(
+                "index".to_string(),
+                RegisteredResponse {
+                    json: Value::from(next),
+                    text: String::new(),
+                },
+            );
+
+            let mut iteration = task.clone();
+            iteration.name = format!("{}[{}]", task.name, next);
+
+            let client = client.clone();
+            let tokens = tokens.clone();
+            let index = next;
+            running.spawn(async move {
+                let result = execute_task(&mut iteration, &client, &tokens, &registry).await;
+                (index, result)
+            });
+            next += 1;
+        }
+
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        let (index, result) = joined?;
+        match result {
+            Ok((json, _)) => outputs[index] = json,
+            Err(e) => {
+                error!("Task `{}[{}]` failed: {}", task.name, index, e);
+                failure.get_or_insert(e);
+            }
+        }
+    }
+
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    let json = Value::Array(outputs);
+    let text = json.to_string();
+    Ok((json, text))
+}
+
+/// Marks a task as finished and queues every dependent whose dependencies are now met.
+fn release_dependents(
+    graph: &TaskGraph,
+    index: usize,
+    pending: &mut [usize],
+    ready: &mut VecDeque<usize>,
+) {
+    for &dependent in &graph.dependents[index] {
+        pending[dependent] -= 1;
+        if pending[dependent] == 0 {
+            ready.push_back(dependent);
+        }
+    }
+}
+
+/// Command line settings for a run.
+#[derive(Default)]
+pub struct RunOptions {
+    /// Maximum number of tasks to run concurrently, overriding `max_concurrency`
+    pub jobs: Option<usize>,
+    /// Variables overriding both workflow and task `vars`
+    pub vars: Map<String, Value>,
+    /// `.env` files loaded after the workflow's `env_file`, overriding it
+    pub env_files: Vec<String>,
+    /// Abort if an `env` or `vars` reference is undefined, as the workflow's `strict`
+    pub strict: bool,
+}
+
+/// Results and registered responses shared by every phase of a run.
+#[derive(Default)]
+struct RunState {
+    results: HashMap<String, Value>,
+    registry: HashMap<String, RegisteredResponse>,
+    /// Command line variables, layered over the `vars` of each task
+    overrides: Map<String, Value>,
+    /// Names of the tasks started so far, to tell which `always` tasks are left
+    started: HashSet<String>,
+}
+
+/// Fills in the settings a task does not set itself.
+fn apply_defaults(task: &mut Task, defaults: &TaskDefaults) {
+    task.timeout = task.timeout.or(defaults.timeout);
+    task.connect_timeout = task.connect_timeout.or(defaults.connect_timeout);
+    task.read_timeout = task.read_timeout.or(defaults.read_timeout);
+    task.tls = match (task.tls.take(), &defaults.tls) {
+        (Some(tls), Some(defaults)) => Some(tls.or(defaults)),
+        (tls, defaults) => tls.or_else(|| defaults.clone()),
+    };
+    task.proxy = task.proxy.take().or_else(|| defaults.proxy.clone());
+    task.no_proxy = task.no_proxy.take().or_else(|| defaults.no_proxy.clone());
+    task.max_redirects = task.max_redirects.or(defaults.max_redirects);
+    for (name, value) in &defaults.headers {
+        if !task.headers.keys().any(|key| key.eq_ignore_ascii_case(name)) {
+            task.headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Reads the files of `JsonSchema` expectations, relative to the workflow, into
+/// their inline `schema`.
+fn load_schemas(task: &mut Task, base_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let until = task.poll.iter_mut().flat_map(|poll| &mut poll.until);
+    for expect in task.expect.iter_mut().chain(until) {
+        if let Expect::JsonSchema { schema: schema @ None, file: Some(file) } = expect {
+            let path = base_dir.join(file.as_str());
+            let text = fs::read_to_string(&path)
+                .map_err(|e| format!("Task `{}`: {}: {}", task.name, path.display(), e))?;
+            // YAML is a superset of JSON, so both formats are accepted
+            let value: Value = serde_yaml::from_str(&text)
+                .map_err(|e| format!("Task `{}`: {}: {}", task.name, path.display(), e))?;
+            *schema = Some(value);
+        }
+    }
+    Ok(())
+}
+
 pub async fn execute_workflow(
     workflow_path: &str,
+    options: RunOptions,
 ) -> Result<HashMap<String, Value>, Box<dyn std::error::Error>> {
     let yaml_str = fs::read_to_string(workflow_path)?;
-    let wf: Workflow = serde_yaml::from_str(&yaml_str)?;
+    let (mut wf, ignored): (Workflow, _) = validate::parse_workflow(&yaml_str)?;
+    let all_tasks = wf.all_tasks();
+    let lines = validate::task_lines(&yaml_str, &wf);
+
+    for issue in validate::unknown_keys(&yaml_str, &wf, &lines, &ignored) {
+        warn!("{}: {}", workflow_path, issue);
+    }
+
+    // Refuse to run anything if the dependency graph is broken
+    let issues = validate::check_graph(&all_tasks, &lines);
+    if !issues.is_empty() {
+        for issue in &issues {
+            error!("{}: {}", workflow_path, issue);
+        }
+        return Err(format!(
+            "Workflow '{}' is invalid: {} problem(s) found",
+            wf.name,
+            issues.len()
+        )
+        .into());
+    }
+
+    // Fill in client profiles and workflow defaults for the settings tasks do not set,
+    // and read schema files
+    let base_dir = Path::new(workflow_path).parent().unwrap_or(Path::new(""));
+    for task in wf.tasks.iter_mut().chain(&mut wf.finally) {
+        if let Some(name) = task.client.clone() {
+            let profile = wf
+                .clients
+                .get(&name)
+                .ok_or_else(|| format!("Task `{}`: unknown client `{}`", task.name, name))?;
+            apply_defaults(task, profile);
+        }
+        apply_defaults(task, &wf.defaults);
+        for file in task.tls.iter_mut().flat_map(Tls::files_mut) {
+            *file = base_dir.join(file.as_str()).to_string_lossy().into_owned();
+        }
+        load_schemas(task, base_dir)?;
+    }
+
+    // Load `.env` files, then make sure every variable the workflow needs is defined
+    let env_files: Vec<String> = wf
+        .env_file
+        .iter()
+        .map(|file| base_dir.join(file).to_string_lossy().into_owned())
+        .chain(options.env_files)
+        .collect();
+    env::load_files(&env_files)?;
+    let missing = vars::missing(&wf, &options.vars);
+    if !missing.is_empty() {
+        if options.strict || wf.strict {
+            return Err(format!(
+                "Workflow '{}' uses undefined variables: {}",
+                wf.name,
+                missing.join(", ")
+            )
+            .into());
+        }
+        warn!("Undefined variables: {}", missing.join(", "));
+    }
+
     info!("Running workflow: {}", wf.name);
 
-    let client = reqwest::Client::new();
-    let mut results: HashMap<String, Value> = HashMap::new();
-    let mut registry: HashMap<String, RegisteredResponse> = HashMap::new();
+    let max_concurrency = max_concurrency(options.jobs, &wf);
+    debug!("Running up to {} tasks concurrently", max_concurrency);
+    let clients = Clients::build(&wf.all_tasks()).await.map_err(|e| e.to_string())?;
+    let mut state = RunState {
+        overrides: options.vars,
+        ..RunState::default()
+    };
+    let workflow_vars = vars::layer(&state.registry, &wf.vars, &state.overrides)
+        .map_err(|e| format!("Workflow '{}': {}", wf.name, e))?;
+    vars::register(&mut state.registry, workflow_vars);
+
+    let run = run_tasks(&wf.tasks, &clients, max_concurrency, &mut state);
+    let mut outcome = match wf.timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, run).await {
+            Ok(outcome) => outcome,
+            Err(_) => {
+                error!("Workflow timed out after {:?}, aborting running tasks", timeout);
+                let leftovers = always_leftovers(&wf.tasks, &state.started);
+                run_always(leftovers, &clients, max_concurrency, &mut state).await;
+                Err(format!("Workflow '{}' timed out", wf.name).into())
+            }
+        },
+        None => run.await,
+    };
+
+    // Cleanup tasks run whatever happened before
+    if !wf.finally.is_empty() {
+        info!("Running {} finally task(s)...", wf.finally.len());
+        if let Err(e) = run_tasks(&wf.finally, &clients, max_concurrency, &mut state).await {
+            error!("Finally tasks failed: {}", e);
+            outcome = outcome.and(Err(e));
+        }
+    }
+
+    match outcome {
+        Ok(()) => {
+            info!("Workflow complete. Results: {:?}", state.results.keys());
+            Ok(state.results)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Number of tasks run at once: `--jobs`, or else the workflow's `max_concurrency`.
+fn max_concurrency(jobs: Option<usize>, wf: &Workflow) -> usize {
+    jobs.unwrap_or(wf.max_concurrency).max(1)
+}
+
+/// The `always` tasks that were not started before the run was cut short.
+fn always_leftovers(tasks: &[Task], started: &HashSet<String>) -> Vec<Task> {
+    tasks
+        .iter()
+        .filter(|task| task.always && !started.contains(&task.name))
+        .cloned()
+        .collect()
+}
+
+/// Runs the `always` tasks left after a failure or a timeout; their own failures are
+/// only logged, the run has already failed.
+async fn run_always(
+    leftovers: Vec<Task>,
+    clients: &Clients,
+    max_concurrency: usize,
+    state: &mut RunState,
+) {
+    if leftovers.is_empty() {
+        return;
+    }
+    info!("Running {} always task(s) after failure...", leftovers.len());
+    if let Err(e) = Box::pin(run_tasks(&leftovers, clients, max_concurrency, state)).await {
+        error!("Always tasks failed: {}", e);
+    }
+}
+
+/// Runs every task as soon as its dependencies are met. After a failure nothing new is
+/// started, except the `always` tasks that have not run yet once running tasks finish.
+async fn run_tasks(
+    tasks: &[Task],
+    clients: &Clients,
+    max_concurrency: usize,
+    state: &mut RunState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let graph = TaskGraph::build(tasks);
+    let RunState {
+        results,
+        registry,
+        overrides,
+        started,
+    } = state;
 
-    for mut task in wf.tasks {
-        match task.kind.as_str() {
-            "http" => {
-                // Resolve environment variables in the task
-                task.resolve_env_vars();
+    let mut pending: Vec<usize> = graph.dependencies.iter().map(Vec::len).collect();
+    let mut ready: VecDeque<usize> = (0..tasks.len()).filter(|&i| pending[i] == 0).collect();
+    let mut running = JoinSet::new();
+    let mut finished = 0;
+    let mut skipped = vec![false; tasks.len()];
+    let mut failure: Option<Box<dyn std::error::Error + Send + Sync>> = None;
 
-                for dep in &task.depends_on {
-                    if !results.contains_key(dep) {
-                        panic!("Missing dependency: {}", dep);
+    loop {
+        // Start every ready task we have room for; stop scheduling after a failure
+        while failure.is_none() && running.len() < max_concurrency {
+            let Some(index) = ready.pop_front() else {
+                break;
+            };
+            let mut task = tasks[index].clone();
+            started.insert(task.name.clone());
+
+            // Layer the task's own variables over the workflow ones
+            let scoped;
+            let task_registry = if task.vars.is_empty() {
+                &*registry
+            } else {
+                match vars::layer(registry, &task.vars, overrides) {
+                    Ok(task_vars) => {
+                        let mut copy = registry.clone();
+                        vars::register(&mut copy, task_vars);
+                        scoped = copy;
+                        &scoped
+                    }
+                    Err(e) => {
+                        failure = Some(format!("Task `{}`: {}", task.name, e).into());
+                        break;
                     }
                 }
+            };
 
-                let (json, text) = execute_task(&mut task, &client, &registry).await?;
+            // Skip the task if its condition is false, or if it has no condition of its
+            // own and one of its dependencies was skipped
+            let skip_reason = match &task.when {
+                Some(condition) => match evaluate_condition(condition, task_registry) {
+                    Ok(true) => None,
+                    Ok(false) => Some(format!("condition `{}` is false", condition)),
+                    Err(e) => {
+                        failure = Some(
+                            format!("Task `{}`: invalid condition `{}`: {}", task.name, condition, e)
+                                .into(),
+                        );
+                        break;
+                    }
+                },
+                None => task
+                    .depends_on
+                    .iter()
+                    .find(|dep| tasks.iter().zip(&skipped).any(|(t, s)| *s && &t.name == *dep))
+                    .map(|dep| format!("dependency `{}` was skipped", dep)),
+            };
+            if let Some(reason) = skip_reason {
+                info!("Skipping task `{}`: {}", task.name, reason);
+                skipped[index] = true;
+                let json = serde_json::json!({ "skipped": true });
                 results.insert(task.name.clone(), json.clone());
-
-                // Register the response if requested
                 if let Some(register_name) = &task.register {
                     registry.insert(
                         register_name.clone(),
                         RegisteredResponse {
-                            json: json.clone(),
-                            text: text.clone(),
+                            json,
+                            text: String::new(),
                         },
                     );
+                }
+                finished += 1;
+                release_dependents(&graph, index, &mut pending, &mut ready);
+                continue;
+            }
+
+            match task.kind.as_str() {
+                "http" => {
+                    let client = match clients.for_task(&task) {
+                        Ok(client) => client,
+                        Err(e) => {
+                            failure = Some(e.into());
+                            break;
+                        }
+                    };
+                    let tokens = clients.tokens.clone();
+                    let registry = task_registry.clone();
+                    running.spawn(async move {
+                        let result = if task.foreach.is_some() {
+                            execute_foreach(&task, &client, &tokens, &registry).await
+                        } else {
+                            execute_task(&mut task, &client, &tokens, &registry).await
+                        };
+                        (index, result)
+                    });
+                }
+                "display" => {
+                    if let Some(variables) = &task.variables {
+                        display_specific_variables(registry, variables);
+                    } else {
+                        display_registered_variables(registry);
+                    }
+                    finished += 1;
+                    release_dependents(&graph, index, &mut pending, &mut ready);
+                }
+                _ => {
+                    info!("Unknown task type: {}", task.kind);
+                    finished += 1;
+                    release_dependents(&graph, index, &mut pending, &mut ready);
+                }
+            }
+        }
+
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        let (index, result) = joined?;
+        let task = &tasks[index];
+        match result {
+            Ok((json, text)) => {
+                results.insert(task.name.clone(), json.clone());
+
+                // Register the response if requested
+                if let Some(register_name) = &task.register {
+                    registry.insert(register_name.clone(), RegisteredResponse { json, text });
                     info!("Registered response as '{}'", register_name);
                 }
+
+                finished += 1;
+                release_dependents(&graph, index, &mut pending, &mut ready);
             }
-            "display" => {
-                if let Some(variables) = task.variables {
-                    display_specific_variables(&registry, &variables);
-                } else {
-                    display_registered_variables(&registry);
+            Err(e) if task.continue_on_error => {
+                warn!("Task `{}` failed, continuing: {}", task.name, e);
+                let mut json = serde_json::json!({ "failed": true, "error": e.to_string() });
+                if let Some(UnmetExpectations(unmet)) = e.downcast_ref() {
+                    json["failures"] = serde_json::to_value(unmet).unwrap_or_default();
+                }
+                results.insert(task.name.clone(), json.clone());
+                if let Some(register_name) = &task.register {
+                    registry.insert(
+                        register_name.clone(),
+                        RegisteredResponse {
+                            json,
+                            text: e.to_string(),
+                        },
+                    );
                 }
+
+                finished += 1;
+                release_dependents(&graph, index, &mut pending, &mut ready);
             }
-            _ => {
-                info!("Unknown task type: {}", task.kind);
+            Err(e) => {
+                error!("Task `{}` failed: {}", task.name, e);
+                if failure.is_none() {
+                    failure = Some(e);
+                    if !running.is_empty() {
+                        info!("Waiting for {} running task(s) to finish...", running.len());
+                    }
+                }
             }
         }
     }
 
-    info!("Workflow complete. Results: {:?}", results.keys());
-    Ok(results)
+    if let Some(e) = failure {
+        let leftovers = always_leftovers(tasks, started);
+        run_always(leftovers, clients, max_concurrency, state).await;
+        return Err(e);
+    }
+    if finished < tasks.len() {
+        let stuck: Vec<&str> = tasks
+            .iter()
+            .zip(&pending)
+            .filter(|(_, p)| **p > 0)
+            .map(|(t, _)| t.name.as_str())
+            .collect();
+        return Err(format!("Dependency cycle between tasks: {}", stuck.join(", ")).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Nothing listens on the discard port, so requests to it fail right away
+    const UNREACHABLE: &str = "http://127.0.0.1:9";
+
+    async fn run(yaml: &str) -> (Result<(), String>, HashMap<String, Value>) {
+        let tasks: Vec<Task> = serde_yaml::from_str(&yaml.replace("UNREACHABLE", UNREACHABLE))
+            .unwrap();
+        let clients = Clients::build(&tasks).await.unwrap();
+        let mut state = RunState::default();
+        let outcome = run_tasks(&tasks, &clients, 4, &mut state).await;
+        (outcome.map_err(|e| e.to_string()), state.results)
+    }
+
+    /// Serves requests after a short delay, returning its URL and the highest number of
+    /// requests it had in progress at once.
+    async fn slow_server() -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (in_progress, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let highest = peak.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let (in_progress, peak) = (in_progress.clone(), peak.clone());
+                tokio::spawn(async move {
+                    let mut request = [0; 1024];
+                    let _ = socket.read(&mut request).await;
+                    let now = in_progress.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    in_progress.fetch_sub(1, Ordering::SeqCst);
+                    let response = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                                    Content-Length: 2\r\nConnection: close\r\n\r\n{}";
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (url, highest)
+    }
+
+    fn independent_tasks(url: &str, count: usize) -> String {
+        (0..count)
+            .map(|i| format!("  - {{ name: t{i}, type: http, method: GET, url: '{url}/{i}' }}\n"))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_independent_tasks_run_concurrently_up_to_the_limit() {
+        let (url, peak) = slow_server().await;
+        let tasks: Vec<Task> = serde_yaml::from_str(&independent_tasks(&url, 6)).unwrap();
+        let clients = Clients::build(&tasks).await.unwrap();
+        let mut state = RunState::default();
+
+        run_tasks(&tasks, &clients, 2, &mut state).await.unwrap();
+
+        assert_eq!(state.results.len(), 6);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_jobs_overrides_max_concurrency() {
+        let wf: Workflow = serde_yaml::from_str("{ name: jobs, max_concurrency: 4, tasks: [] }")
+            .unwrap();
+
+        assert_eq!(max_concurrency(None, &wf), 4);
+        assert_eq!(max_concurrency(Some(1), &wf), 1);
+        assert_eq!(max_concurrency(Some(0), &wf), 1);
+    }
+
+    #[tokio::test]
+    async fn test_continue_on_error_records_failure() {
+        let (outcome, results) = run(
+            r#"
+            - { name: a, type: http, method: GET, url: UNREACHABLE, retries: 0,
+                continue_on_error: true }
+            - { name: b, type: http, method: GET, url: UNREACHABLE, retries: 0,
+                continue_on_error: true, depends_on: [a] }
+        "#,
+        )
+        .await;
+
+        assert!(outcome.is_ok());
+        assert_eq!(results["a"]["failed"], true);
+        assert!(results["a"]["error"].is_string());
+        assert_eq!(results["b"]["failed"], true);
+    }
+
+    #[tokio::test]
+    async fn test_always_runs_after_failure() {
+        let (outcome, results) = run(
+            r#"
+            - { name: a, type: http, method: GET, url: UNREACHABLE, retries: 0 }
+            - { name: b, type: http, method: GET, url: UNREACHABLE, retries: 0, depends_on: [a] }
+            - { name: c, type: http, method: GET, url: UNREACHABLE, retries: 0, depends_on: [a],
+                always: true, continue_on_error: true }
+            - { name: d, type: display, always: true }
+        "#,
+        )
+        .await;
+
+        assert!(outcome.is_err());
+        assert!(!results.contains_key("b"));
+        assert_eq!(results["c"]["failed"], true);
+    }
+
+    #[tokio::test]
+    async fn test_skip_propagates_to_dependents() {
+        let (outcome, results) = run(
+            r#"
+            - { name: a, type: display, when: "1 == 2" }
+            - { name: b, type: display, depends_on: [a] }
+            - { name: c, type: http, method: GET, url: UNREACHABLE, retries: 0, depends_on: [b],
+                when: "1 == 1", continue_on_error: true }
+        "#,
+        )
+        .await;
+
+        assert!(outcome.is_ok());
+        assert_eq!(results["a"]["skipped"], true);
+        assert_eq!(results["b"]["skipped"], true);
+        assert_eq!(results["c"]["failed"], true);
+    }
+
+    #[tokio::test]
+    async fn test_always_runs_after_timeout() {
+        // Neither listener answers: the first stalls the workflow, the second only
+        // records that the `always` task connected
+        let stall = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let cleanup = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let name = format!("crabflow-timeout-{}.yaml", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let yaml = format!(
+            r#"
+name: timeout
+timeout: 300ms
+tasks:
+  - {{ name: slow, type: http, method: GET, url: "http://{}", retries: 0 }}
+  - {{ name: cleanup, type: http, method: GET, url: "http://{}", retries: 0, timeout: 200ms,
+      depends_on: [slow], always: true, continue_on_error: true }}
+"#,
+            stall.local_addr().unwrap(),
+            cleanup.local_addr().unwrap()
+        );
+        fs::write(&path, yaml).unwrap();
+
+        let outcome = execute_workflow(path.to_str().unwrap(), RunOptions::default()).await;
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(outcome.unwrap_err().to_string(), "Workflow 'timeout' timed out");
+        cleanup.set_nonblocking(true).unwrap();
+        assert!(cleanup.accept().is_ok());
+    }
 }